@@ -0,0 +1,237 @@
+// Audit mode: flag files whose name hides what they are, e.g. executables
+// named like documents, for security triage
+
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+use super::is_executable;
+use super::modes;
+use super::MagicInfo;
+use super::Types;
+
+
+// libmagic MIME types of programs and scripts that run on their own
+const EXECUTABLE_MIMES: &[&str] = &[
+  "application/x-dosexec",
+  "application/x-msdownload",
+  "application/vnd.microsoft.portable-executable",
+  "application/x-executable",
+  "application/x-pie-executable",
+  "application/x-sharedlib",
+  "application/x-mach-binary",
+  "application/x-elf",
+  "application/x-sh",
+  "application/x-shellscript",
+  "text/x-shellscript",
+  "text/x-msdos-batch",
+  "text/x-python",
+  "text/x-script.python",
+  "text/x-perl",
+  "text/x-ruby",
+  "text/x-php",
+  "application/x-ms-shortcut",
+  "application/hta",
+];
+
+// Extensions Windows runs (or opens with a script host) on a double click
+const EXECUTABLE_EXTS: &[&str] = &[
+  "exe", "com", "scr", "pif", "cpl", "dll", "msi", "msp", "bat", "cmd", "ps1", "psm1", "vbs",
+  "vbe", "js", "jse", "wsf", "wsh", "hta", "lnk", "jar", "reg", "app", "run",
+];
+
+// Bidirectional formatting characters that can reverse how a name displays,
+// e.g. "invoice\u{202e}fdp.exe" shows as "invoiceexe.pdf"
+const BIDI_CONTROLS: &[char] = &[
+  '\u{202a}', '\u{202b}', '\u{202c}', '\u{202d}', '\u{202e}', '\u{2066}', '\u{2067}',
+  '\u{2068}', '\u{2069}', '\u{200e}', '\u{200f}',
+];
+
+pub struct Auditor {
+  exec_exts: HashSet<String>,
+  data_exts: HashSet<String>,
+}
+
+impl Auditor {
+  pub(crate) fn new(types: &Types) -> Auditor {
+    let usable = |e: &&&str| **e != "*" && **e != "?";
+
+    let exec_exts: HashSet<String> = EXECUTABLE_EXTS
+      .iter()
+      .chain(
+        EXECUTABLE_MIMES
+          .iter()
+          .filter_map(|m| types.mime.get(m))
+          .chain(types.shebang.iter().map(|(_, exts)| *exts))
+          .flatten()
+          .filter(usable),
+      )
+      .map(|e| e.to_string())
+      .collect();
+
+    let data_exts: HashSet<String> = types
+      .mime
+      .values()
+      .flatten()
+      .filter(usable)
+      .filter(|e| !exec_exts.contains(**e))
+      .map(|e| e.to_string())
+      .collect();
+
+    Auditor { exec_exts, data_exts }
+  }
+
+  // Whether the extension suggests a document, media or other data file
+  fn is_data_ext(&self, ext: &str) -> bool {
+    self.data_exts.contains(&ext.to_lowercase())
+  }
+
+  fn is_exec_ext(&self, ext: &str) -> bool {
+    self.exec_exts.contains(&ext.to_lowercase())
+  }
+
+  // Everything suspicious about the file, empty if nothing is
+  pub(crate) fn findings(&self, path: &Path, basename: &str, m: &MagicInfo) -> Vec<String> {
+    let mut findings: Vec<String> = vec![];
+
+    let executable = EXECUTABLE_MIMES.contains(&&*m.mime) || !m.interp.is_empty();
+
+    // Descriptions of executables go on with build details
+    let what = format!("{} ({})", m.desc.split(',').next().unwrap_or_default(), m.mime);
+
+    for c in basename.chars().filter(|c| BIDI_CONTROLS.contains(c)) {
+      findings.push(format!("bidirectional control character {} in name", c.escape_unicode()));
+    }
+
+    // Padding with spaces pushes the real extension out of sight
+    let parts: Vec<&str> = basename.split('.').skip(1).map(|p| p.trim()).collect();
+
+    if let [.., inner, outer] = parts[..] {
+      if self.is_data_ext(inner) && self.is_exec_ext(outer) {
+        findings.push(format!("double extension .{}.{}", inner, outer));
+      }
+    }
+
+    if let Some(ext) = parts.last() {
+      if executable && self.is_data_ext(ext) {
+        findings.push(format!("{} disguised as .{}", what, ext));
+      }
+    }
+
+    // Only types that are never run, as e.g. text may well be a script
+    if modes::wants_exec(m, &m.mime) == Some(false) && is_executable(path) {
+      findings.push(format!("exec bit set on {}", what));
+    }
+
+    findings
+  }
+}
+
+// The path with bidirectional control characters escaped, so that it
+// displays as it is
+pub fn escape_path(path: &str) -> String {
+  path
+    .chars()
+    .map(|c| match BIDI_CONTROLS.contains(&c) {
+      true  => c.escape_unicode().to_string(),
+      false => c.to_string(),
+    })
+    .collect()
+}
+
+// Numbered suffixes tried when a quarantined file's name is taken
+const MAX_QUARANTINE_SUFFIX: usize = 999;
+
+// Where the `n`th attempt at quarantining `basename` moves it to: the name
+// itself, then with a numbered suffix
+fn quarantine_dest(dir: &Path, basename: &str, n: usize) -> PathBuf {
+  match n {
+    0 => dir.join(basename),
+    n => dir.join(format!("{}.{}", basename, n)),
+  }
+}
+
+// Move the file at `path` into `dir`, copying it across file systems, but
+// never over an existing file: the next numbered suffix is tried instead
+pub fn quarantine(path: &Path, basename: &str, dir: &Path) -> io::Result<PathBuf> {
+  for n in 0..=MAX_QUARANTINE_SUFFIX {
+    let dest = quarantine_dest(dir, basename, n);
+
+    match move_new(path, &dest) {
+      Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+      r                                                  => return r.map(|_| dest),
+    }
+  }
+
+  Err(io::Error::new(io::ErrorKind::AlreadyExists, "all destinations exist"))
+}
+
+// Where quarantine() would move the file, for dry runs
+pub fn quarantine_preview(basename: &str, dir: &Path) -> PathBuf {
+  (0..=MAX_QUARANTINE_SUFFIX)
+    .map(|n| quarantine_dest(dir, basename, n))
+    .find(|d| !d.exists())
+    .unwrap_or_else(|| quarantine_dest(dir, basename, 0))
+}
+
+// Move the file at `path` to `dest` unless it exists, checked by the same
+// system call that creates it: a hard link, or where that is impossible (e.g.
+// across file systems) a copy created exclusively
+fn move_new(path: &Path, dest: &Path) -> io::Result<()> {
+  match fs::hard_link(path, dest) {
+    Ok(())                                              => return fs::remove_file(path),
+    Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Err(e),
+    Err(_)                                              => {}
+  }
+
+  let mut from = fs::File::open(path)?;
+  let mut to = fs::OpenOptions::new().write(true).create_new(true).open(dest)?;
+
+  let copied = io::copy(&mut from, &mut to)
+    .and_then(|_| from.metadata())
+    .and_then(|md| to.set_permissions(md.permissions()));
+
+  if let Err(e) = copied {
+    let _ = fs::remove_file(dest);
+    return Err(e);
+  }
+
+  fs::remove_file(path)
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::env;
+  use std::process;
+
+  #[test]
+  fn quarantine_never_overwrites() {
+    let dir = env::temp_dir().join(format!("fixext-quarantine-{}", process::id()));
+    let src = env::temp_dir().join(format!("fixext-suspicious-{}", process::id()));
+
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("invoice.pdf"), b"first").unwrap();
+    fs::write(dir.join("invoice.pdf.1"), b"second").unwrap();
+    fs::write(&src, b"third").unwrap();
+
+    let preview = quarantine_preview("invoice.pdf", &dir);
+    let dest = quarantine(&src, "invoice.pdf", &dir).unwrap();
+
+    let contents: Vec<Vec<u8>> = ["invoice.pdf", "invoice.pdf.1", "invoice.pdf.2"]
+      .iter()
+      .map(|n| fs::read(dir.join(n)).unwrap())
+      .collect();
+    let moved = !src.exists();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(preview, dir.join("invoice.pdf.2"));
+    assert_eq!(dest, dir.join("invoice.pdf.2"));
+    assert_eq!(contents, [&b"first"[..], b"second", b"third"]);
+    assert!(moved);
+  }
+}
@@ -1,13 +1,12 @@
 use std::collections::HashSet;
-use std::path;
 use std::env;
+use std::fmt::Write;
 use std::fs;
 use std::io;
 use std::io::BufRead;
 use std::path::Path;
 use std::path::PathBuf;
 use std::vec::Vec;
-use std::process::Command;
 
 use glob::glob;
 
@@ -16,40 +15,21 @@ use regex::Regex;
 use winres::WindowsResource;
 
 
-fn main() {
-  let target = env::var("TARGET").unwrap();
-  let target_arch = target.split('-').next().unwrap();
-  let windows = target.contains("windows");
+// Reads all data/*.KIND.types files into (key, extensions) pairs, skipping
+// comments, duplicate keys and lines rejected by `check`
+fn read_types(
+  kind: &str,
+  separator: char,
+  check: &dyn Fn(&str, &[String]) -> Result<(), &'static str>,
+) -> Vec<(String, Vec<String>)> {
+  let mut types_set: HashSet<String> = HashSet::new();
+  let mut types: Vec<(String, Vec<String>)> = vec![];
 
-  let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
-  let desc_types_cbor_file = out_dir.join("desc.types.cbor");
-  let mime_types_cbor_file = out_dir.join("mime.types.cbor");
+  let comment_r = Regex::new(r"^\s*#").unwrap();
+  let empty_r   = Regex::new(r"^\s*$").unwrap();
 
-  if windows {
-    let magic_file = out_dir.join("magic.mgc");
-    let magic_file_src = "vendor/build/magic.mgc".to_string();
-    println!("rerun-if-changed={}", magic_file.to_string_lossy());
-    fs::copy(magic_file_src, magic_file).expect("could not find magic.mgc (run `./windist.sh`)");
-  }
-
-  println!("rerun-if-changed=data");
-
-  let data_dir = Path::new("./data");
-  let data_files = fs::read_dir(data_dir).unwrap();
-
-  for f in data_files {
-    let name = f.unwrap().file_name().into_string().unwrap();
-    println!("rerun-if-changed=data/{}", name);
-  }
-
-  println!("rerun-if-changed={}", desc_types_cbor_file.to_string_lossy());
-  println!("rerun-if-changed={}", mime_types_cbor_file.to_string_lossy());
-
-  let mut desc_types_set: HashSet<String> = HashSet::new();
-  let mut desc_types: Vec<(String, Vec<String>)> = vec![];
-
-  for dtf in glob("data/*.desc.types").unwrap() {
-    let p = match dtf {
+  for tf in glob(&format!("data/*.{}.types", kind)).unwrap() {
+    let p = match tf {
       Ok(path) => path,
       e        => panic!("glob returned an error: {:?}", e)
     };
@@ -61,98 +41,221 @@ fn main() {
       let line = line.unwrap();
       let l = line.trim_start().trim_end();
 
-      let comment_r = Regex::new(r"^\s*#").unwrap();
-      let empty_r   = Regex::new(r"^\s*$").unwrap();
-
-      eprintln!("desc.types line: {}", l);
+      eprintln!("{}.types line: {}", kind, l);
 
       if empty_r.is_match(l) || comment_r.is_match(l) {
         eprintln!("(line is empty or a comment, skipping)");
         continue;
       }
 
-      let splits: Vec<String> = (*l).split('\t').map(|s| s.to_string()).collect();
+      let splits: Vec<String> = (*l).split(separator).map(|s| s.to_string()).collect();
 
-      let regex = splits[0].to_string();
-      let exts  = (&splits[1..]).to_vec();
+      let key  = splits[0].to_string();
+      let exts = (&splits[1..]).to_vec();
 
-      eprintln!("Description: {}, exts: {:?}", regex, exts);
+      eprintln!("Key: {}, exts: {:?}", key, exts);
 
-      if Regex::new(&*regex).is_err() {
-        eprintln!("Description is an invalid regex, skipping");
+      if let Err(e) = check(&key, &exts) {
+        eprintln!("{}, skipping", e);
         continue;
       }
 
-      if desc_types_set.contains(&regex) {
-        eprintln!("Description already processed, skipping");
+      if types_set.contains(&key) {
+        eprintln!("Key has already been processed, skipping: {}", key);
         continue;
       }
 
-      desc_types_set.insert(regex.clone());
+      types_set.insert(key.clone());
 
-      desc_types.push((regex, exts));
+      types.push((key, exts));
     }
   }
 
-  let desc_types_cbor = fs::File::create(desc_types_cbor_file).unwrap();
-  serde_cbor::to_writer(desc_types_cbor, &desc_types).unwrap();
+  types
+}
 
-  let mut mime_types_set: HashSet<String> = HashSet::new();
-  let mut mime_types: Vec<(String, Vec<String>)> = vec![];
+// "text/plain;charset=binary" -> "text/plain; charset=binary", the way
+// fixext looks MIME types up
+fn normalize_mime(mime: &str) -> String {
+  mime.split(';').map(str::trim).collect::<Vec<&str>>().join("; ")
+}
 
-  for mtf in glob("data/*.mime.types").unwrap() {
-    let p = match mtf {
-      Ok(path) => path,
-      e        => panic!("glob returned an error: {:?}", e)
-    };
+// Sorts types by key for binary search, keeping the first of the keys that
+// are the same once normalized
+fn sorted(
+  types: &[(String, Vec<String>)],
+  normalize: &dyn Fn(&str) -> String,
+) -> Vec<(String, Vec<String>)> {
+  let mut keys: HashSet<String> = HashSet::new();
+
+  let mut result: Vec<(String, Vec<String>)> = types
+    .iter()
+    .map(|(key, exts)| (normalize(key), exts.clone()))
+    .filter(|(key, _)| keys.insert(key.clone()))
+    .collect();
+
+  result.sort_by(|a, b| a.0.cmp(&b.0));
+  result
+}
 
-    let f = fs::File::open(p).unwrap();
-    let r = io::BufReader::new(f);
+// Writes (key, extensions) pairs as a static table; Debug formatting of
+// strings gives valid Rust literals
+fn write_table(out: &mut String, name: &str, types: &[(String, Vec<String>)]) {
+  writeln!(out, "pub static {}: Table = &[", name).unwrap();
 
-    for line in r.lines() {
-      let line = line.unwrap();
-      let l = line.trim_start().trim_end();
+  for (key, exts) in types {
+    writeln!(out, "  ({:?}, &{:?}),", key, exts).unwrap();
+  }
 
-      let comment_r = Regex::new(r"^\s*#").unwrap();
-      let empty_r   = Regex::new(r"^\s*$").unwrap();
+  writeln!(out, "];\n").unwrap();
+}
 
-      eprintln!("mime.types line: {}", l);
+fn write_list(out: &mut String, name: &str, list: &[String]) {
+  writeln!(out, "pub static {}: &[&str] = &{:?};\n", name, list).unwrap();
+}
 
-      if empty_r.is_match(l) || comment_r.is_match(l) {
-        eprintln!("(line is empty or a comment, skipping)");
-        continue;
-      }
 
-      let splits: Vec<String> = (*l).split(' ').map(|s| s.to_string()).collect();
+fn main() {
+  let target = env::var("TARGET").unwrap();
+  let target_arch = target.split('-').next().unwrap();
+  let windows = target.contains("windows");
 
-      let mime = splits[0].to_string();
-      let exts = (&splits[1..]).to_vec();
+  let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+  let tables_file = out_dir.join("tables.rs");
 
-      eprintln!("MIME: {}, exts: {:?}", mime, exts);
+  if windows {
+    let magic_file = out_dir.join("magic.mgc");
+    let magic_file_src = "vendor/build/magic.mgc".to_string();
+    println!("rerun-if-changed={}", magic_file.to_string_lossy());
+    fs::copy(magic_file_src, magic_file).expect("could not find magic.mgc (run `./windist.sh`)");
+  }
 
-      if mime.is_empty() {
-        eprintln!("MIME is empty, skipping");
-        continue;
-      }
+  println!("rerun-if-changed=data");
 
-      if exts.is_empty() {
-        eprintln!("MIME is associated with no extensions, skipping");
-        continue;
-      }
+  let data_dir = Path::new("./data");
+  let data_files = fs::read_dir(data_dir).unwrap();
 
-      if mime_types_set.contains(&mime) {
-        eprintln!("MIME has already been processed, skipping: {}", mime);
-        continue;
+  for f in data_files {
+    let name = f.unwrap().file_name().into_string().unwrap();
+    println!("rerun-if-changed=data/{}", name);
+  }
+
+  println!("rerun-if-changed={}", tables_file.to_string_lossy());
+
+  let desc_types = read_types("desc", '\t', &|regex, _| {
+    if Regex::new(regex).is_err() {
+      return Err("Description is an invalid regex");
+    }
+
+    Ok(())
+  });
+
+  let mime_types = read_types("mime", ' ', &|mime, exts| {
+    if mime.is_empty() {
+      return Err("MIME is empty");
+    }
+
+    if exts.is_empty() {
+      return Err("MIME is associated with no extensions");
+    }
+
+    Ok(())
+  });
+
+  let mut tables = String::from("// Generated by build.rs from data/*.types\n\n");
+
+  write_table(&mut tables, "DESC_TYPES", &desc_types);
+  write_table(&mut tables, "MIME_TYPES", &sorted(&mime_types, &normalize_mime));
+
+  let compound_types = read_types("compound", ' ', &|compound, _| {
+    if !compound.contains('.') {
+      return Err("Compound extension has no dots");
+    }
+
+    Ok(())
+  });
+
+  // A compound extension with no others stands for itself
+  let compound_types: Vec<(String, Vec<String>)> = compound_types
+    .into_iter()
+    .map(|(compound, exts)| {
+      let exts = if exts.is_empty() { vec![compound.clone()] } else { exts };
+      (compound, exts)
+    })
+    .collect();
+
+  // All multi-part extensions, longest first
+  let multi_exts: Vec<String> = {
+    let mut result: Vec<String> = compound_types
+      .iter()
+      .flat_map(|(compound, exts)| std::iter::once(compound).chain(exts))
+      .filter(|e| e.contains('.'))
+      .cloned()
+      .collect();
+
+    result.sort_by(|a, b| b.matches('.').count().cmp(&a.matches('.').count()).then(a.cmp(b)));
+    result.dedup();
+    result
+  };
+
+  write_table(&mut tables, "COMPOUND_TYPES", &sorted(&compound_types, &str::to_string));
+  write_list(&mut tables, "MULTI_EXTS", &multi_exts);
+
+  let generic_types = read_types("generic", '\t', &|generic, _| {
+    if generic.len() > 1 && generic.starts_with('/') && generic.ends_with('/') {
+      if Regex::new(&generic[1..generic.len() - 1]).is_err() {
+        return Err("Description is an invalid regex");
       }
+    } else if !generic.contains('/') {
+      return Err("Neither a MIME type nor a /description/");
+    }
+
+    Ok(())
+  });
+
+  // Descriptions are written as /REGEX/, checked above
+  let (generic_descs, generic_mimes): (Vec<String>, Vec<String>) = {
+    let (descs, mimes): (Vec<String>, Vec<String>) =
+      generic_types.into_iter().map(|(generic, _)| generic).partition(|g| g.starts_with('/'));
 
-      mime_types_set.insert(mime.clone());
+    (
+      descs.iter().map(|d| d[1..d.len() - 1].to_string()).collect(),
+      mimes.iter().map(|m| normalize_mime(m)).collect(),
+    )
+  };
 
-      mime_types.push((mime, exts));
+  write_list(&mut tables, "GENERIC_DESCS", &generic_descs);
+  write_list(&mut tables, "GENERIC_MIMES", &generic_mimes);
+
+  let compat_types = read_types("compat", ' ', &|mime, exts| {
+    if !mime.contains('/') {
+      return Err("MIME does not contain a forward slash");
     }
-  }
 
-  let mime_types_cbor = fs::File::create(mime_types_cbor_file).unwrap();
-  serde_cbor::to_writer(mime_types_cbor, &mime_types).unwrap();
+    if exts.is_empty() {
+      return Err("MIME has no compatible extensions");
+    }
+
+    Ok(())
+  });
+
+  write_table(&mut tables, "COMPAT_TYPES", &sorted(&compat_types, &normalize_mime));
+
+  let shebang_types = read_types("shebang", ' ', &|interpreter, exts| {
+    if interpreter.contains('/') {
+      return Err("Interpreter is a path, not a command name");
+    }
+
+    if exts.is_empty() {
+      return Err("Interpreter is associated with no extensions");
+    }
+
+    Ok(())
+  });
+
+  write_table(&mut tables, "SHEBANG_TYPES", &sorted(&shebang_types, &str::to_string));
+
+  fs::write(tables_file, tables).unwrap();
 
   if windows {
     println!("cargo:rustc-link-search=native=vendor/build/{}/", target_arch);
@@ -0,0 +1,281 @@
+// Persistent detection cache: what libmagic and the sniffers found in each
+// file, keyed by device and inode, and valid as long as the size and mtime
+// are the same, so unchanged files need not be opened again
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::io;
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use super::sniff;
+
+
+const CACHE_FILE: &str = "fixext.cbor";
+
+// Entries not used for this long are dropped, and so are the least recently
+// used ones beyond MAX_ENTRIES, as files that are gone are never looked up;
+// entries used in this run are kept however many there are
+const MAX_AGE_SECS: u64 = 90 * 24 * 60 * 60;
+const MAX_ENTRIES: usize = 200_000;
+
+// How old the last use of an entry gets before it is updated, so that runs
+// over unchanged files do not rewrite the cache every time
+const USED_RESOLUTION_SECS: u64 = 24 * 60 * 60;
+
+// Everything read from a file's content; the optional parts are only filled
+// in when needed
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Detected {
+  pub desc:    String,
+  pub mime:    String,
+  pub ext:     String,
+  pub charset: String,
+  pub zdesc:   Option<String>,
+  pub zmime:   Option<String>,
+  pub interp:  Option<String>,
+  pub sniffed: Option<Option<sniff::Sniffed>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+  size:     u64,
+  mtime_ns: i64,
+  used:     u64,
+  detected: Detected,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CacheFile {
+  identity: String,
+  entries:  HashMap<(u64, u64), Entry>,
+}
+
+pub struct Cache {
+  path:    PathBuf,
+  file:    CacheFile,
+  dirty:   bool,
+  now:     u64,
+  // Keys looked up or stored in this run
+  touched: HashSet<(u64, u64)>,
+}
+
+// $XDG_CACHE_HOME, or ~/.cache
+pub fn default_path() -> Option<PathBuf> {
+  env::var_os("XDG_CACHE_HOME")
+    .filter(|d| Path::new(d).is_absolute())
+    .map(PathBuf::from)
+    .or_else(|| env::var_os("HOME").map(|h| Path::new(&h).join(".cache")))
+    .map(|d| d.join(CACHE_FILE))
+}
+
+// What the cached results depend on besides the files themselves: the magic
+// databases (a colon-separated list of paths, or None for the built-in one),
+// and the version and options of fixext
+pub fn identity(magic: Option<&str>, version: &str, options: &str) -> String {
+  let db = match magic {
+    Some(paths) => env::split_paths(paths)
+      .map(|p| match fs::metadata(&p) {
+        Ok(md) => format!("{} {} {}", p.display(), md.len(), mtime_ns(&md)),
+        Err(_) => format!("{} ?", p.display()),
+      })
+      .collect::<Vec<String>>()
+      .join(":"),
+    None => String::from("built-in"),
+  };
+
+  format!("fixext {} ({}) with {}", version, options, db)
+}
+
+#[cfg(unix)]
+fn mtime_ns(md: &fs::Metadata) -> i64 {
+  md.mtime().saturating_mul(1_000_000_000).saturating_add(md.mtime_nsec())
+}
+
+#[cfg(not(unix))]
+fn mtime_ns(md: &fs::Metadata) -> i64 {
+  md.modified()
+    .ok()
+    .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+    .map_or(0, |d| d.as_nanos() as i64)
+}
+
+// The key and validity of the cache entry for the file at `path`; there are
+// no inode numbers outside of Unix
+#[cfg(unix)]
+fn stat(path: &Path) -> Option<((u64, u64), u64, i64)> {
+  let md = fs::metadata(path).ok().filter(|md| md.is_file())?;
+  Some(((md.dev(), md.ino()), md.len(), mtime_ns(&md)))
+}
+
+#[cfg(not(unix))]
+fn stat(_path: &Path) -> Option<((u64, u64), u64, i64)> {
+  None
+}
+
+impl Cache {
+  // Load the cache at `path`, or start over if it is missing, unreadable, or
+  // was made with a different identity
+  pub fn open(path: PathBuf, identity: String) -> Cache {
+    let file = fs::read(&path)
+      .ok()
+      .and_then(|b| serde_cbor::from_slice::<CacheFile>(&b).ok())
+      .filter(|f| f.identity == identity)
+      .unwrap_or(CacheFile { identity, entries: HashMap::new() });
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+
+    Cache { path, file, dirty: false, now, touched: HashSet::new() }
+  }
+
+  pub fn get(&mut self, path: &Path) -> Option<Detected> {
+    let (key, size, mtime_ns) = stat(path)?;
+    let entry = self.file.entries.get_mut(&key)?;
+
+    if entry.size != size || entry.mtime_ns != mtime_ns {
+      return None;
+    }
+
+    self.touched.insert(key);
+
+    if self.now.saturating_sub(entry.used) > USED_RESOLUTION_SECS {
+      entry.used = self.now;
+      self.dirty = true;
+    }
+
+    Some(entry.detected.clone())
+  }
+
+  pub fn put(&mut self, path: &Path, detected: Detected) {
+    if let Some((key, size, mtime_ns)) = stat(path) {
+      self.file.entries.insert(key, Entry { size, mtime_ns, used: self.now, detected });
+      self.touched.insert(key);
+      self.dirty = true;
+    }
+  }
+
+  // Drop entries unused for MAX_AGE_SECS, then the least recently used ones
+  // beyond MAX_ENTRIES, never those touched in this run
+  fn prune(&mut self) {
+    let now = self.now;
+    let before = self.file.entries.len();
+    let touched = &self.touched;
+
+    self.file.entries.retain(|_, e| now.saturating_sub(e.used) <= MAX_AGE_SECS);
+
+    if self.file.entries.len() > MAX_ENTRIES {
+      let mut by_use: Vec<((u64, u64), u64)> = self
+        .file
+        .entries
+        .iter()
+        .filter(|(key, _)| !touched.contains(key))
+        .map(|(key, e)| (*key, e.used))
+        .collect();
+      by_use.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+
+      for (key, _) in by_use.iter().skip(MAX_ENTRIES.saturating_sub(touched.len())) {
+        self.file.entries.remove(key);
+      }
+    }
+
+    if self.file.entries.len() != before {
+      self.dirty = true;
+    }
+  }
+
+  // Write the cache through a temporary file, so that a concurrent or
+  // interrupted run never leaves a truncated one
+  pub fn save(&mut self) -> io::Result<()> {
+    self.prune();
+
+    if !self.dirty {
+      return Ok(());
+    }
+
+    if let Some(dir) = self.path.parent() {
+      fs::create_dir_all(dir)?;
+    }
+
+    let tmp = self.path.with_extension(format!("{}.tmp", std::process::id()));
+    let b = serde_cbor::to_vec(&self.file).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+    fs::write(&tmp, b)?;
+    fs::rename(&tmp, &self.path)
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn cache(now: u64, used: &[u64]) -> Cache {
+    let entry = |used: u64| Entry { size: 0, mtime_ns: 0, used, detected: Detected::default() };
+    let entries = used.iter().enumerate().map(|(i, &used)| ((0, i as u64), entry(used))).collect();
+
+    Cache {
+      path: PathBuf::new(),
+      file: CacheFile { identity: String::new(), entries },
+      dirty: false,
+      now,
+      touched: HashSet::new(),
+    }
+  }
+
+  #[test]
+  fn old_entries_are_pruned() {
+    let now = 1000 * 24 * 60 * 60;
+    let mut c = cache(now, &[now, now - MAX_AGE_SECS, now - MAX_AGE_SECS - 1, 0]);
+
+    c.prune();
+    assert!(c.dirty);
+    assert_eq!(c.file.entries.len(), 2);
+
+    let mut c = cache(now, &[now]);
+    c.prune();
+    assert!(!c.dirty);
+  }
+
+  #[test]
+  fn least_recently_used_entries_are_pruned() {
+    let now = MAX_AGE_SECS;
+    let used: Vec<u64> = (0..MAX_ENTRIES as u64 + 10).collect();
+    let mut c = cache(now, &used);
+
+    c.prune();
+    assert_eq!(c.file.entries.len(), MAX_ENTRIES);
+    assert!(c.file.entries.values().all(|e| e.used >= 10));
+  }
+
+  #[test]
+  fn touched_entries_are_kept() {
+    let now = MAX_AGE_SECS;
+    let used: Vec<u64> = (0..MAX_ENTRIES as u64 + 10).collect();
+    let mut c = cache(now, &used);
+
+    // More files in this run than MAX_ENTRIES: all but the five least recently used
+    c.touched = (5..MAX_ENTRIES as u64 + 10).map(|i| (0, i)).collect();
+
+    c.prune();
+    assert_eq!(c.file.entries.len(), MAX_ENTRIES + 5);
+    assert!(c.file.entries.keys().all(|key| c.touched.contains(key)));
+  }
+
+  #[test]
+  fn identities() {
+    let built_in = identity(None, "1.0", "-z");
+    assert_eq!(built_in, "fixext 1.0 (-z) with built-in");
+
+    let missing = identity(Some("/no/such/a.mgc:/no/such/b.mgc"), "1.0", "");
+    assert!(missing.ends_with("with /no/such/a.mgc ?:/no/such/b.mgc ?"));
+  }
+}
@@ -7,9 +7,6 @@ const VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
 const AUTHORS: Option<&'static str> = option_env!("CARGO_PKG_AUTHORS");
 const DESCRIP: Option<&'static str> = option_env!("CARGO_PKG_DESCRIPTION");
 
-const MIME_TYPES_CBOR: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/mime.types.cbor"));
-const DESC_TYPES_CBOR: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/desc.types.cbor"));
-
 #[cfg(not(windows))]
 const DEFAULT_MGC: Option<&'static str> = option_env!("DEFAULT_MAGIC_FILE_PATH");
 #[cfg(not(windows))]
@@ -20,21 +17,47 @@ const DEFAULT_MGC: Option<&'static str> = Some("");
 #[cfg(windows)]
 const BUILTIN_MGC: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/magic.mgc"));
 
+const EXIT_OK:    i32 = 0;
+const EXIT_FIXES: i32 = 1;
+const EXIT_ERROR: i32 = 2;
+const EXIT_USAGE: i32 = 64;
+
+const DEFAULT_LANG_THRESHOLD: u32 = 60;
+
+// How much of each file is read once for every cookie and sniffer, unless
+// --bytes-max says otherwise
+const DEFAULT_PREFIX_LEN: u64 = 1024 * 1024;
+
+// Watched files have just been written, so wait for writers that pause for
+// longer than the watcher settles
+const DEFAULT_WATCH_STABLE_FOR: u64 = 5;
 
+
+use std::cell::Cell;
+use std::cell::RefCell;
 use std::clone::Clone;
 use std::collections::HashMap;
 use std::convert::TryInto;
+use std::env;
+use std::fmt;
 use std::fs;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
 use std::path;
 use std::path::Component::*;
 use std::path::PathBuf;
 use std::path::Path;
 use std::process;
+use std::str::FromStr;
+use std::time::Duration;
 use std::vec::Vec;
 
 use magic::CookieFlags;
 
+use glob::Pattern;
+
 use regex::Regex;
+use regex::RegexSet;
 
 use ansi_term::ANSIString;
 use ansi_term::Style;
@@ -44,23 +67,83 @@ use clap::clap_app;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
+mod audit;
+mod cache;
+mod inprogress;
+mod libmagic;
+mod modes;
+mod prefix;
+mod sniff;
+mod tables;
+mod watch;
+
 
 struct Cookie {
-  desc: magic::Cookie,
-  mime: magic::Cookie,
+  desc: libmagic::Cookie,
+  mime: libmagic::Cookie,
+  ext:  libmagic::Cookie,
+  enc:  libmagic::Cookie,
+  zdesc: libmagic::Cookie,
+  zmime: libmagic::Cookie,
 }
 
 #[derive(Debug)]
 enum MagicMatch {
-  Description(Regex, Vec<String>),
+  Description(String, Vec<String>),
   Mime(String, Vec<String>),
+  Extension(Vec<String>),
+  Compressed(String, Vec<String>),
+  Sniffed(String, Vec<String>),
+  Shebang(String, Vec<String>),
   None,
 }
 
+#[derive(Debug, Default)]
+struct MagicInfo {
+  desc:  String,
+  mime:  String,
+  dexts: Vec<String>,
+  mexts: Vec<String>,
+  xexts: Vec<String>,
+  zdesc: String,
+  zmime: String,
+  charset: String,
+  sniffed: sniff::Sniffed,
+  interp:  String,
+}
+
+impl MagicInfo {
+  // One NUL-separated -I record, see fixext(1)
+  fn record(&self, path: &str, new_path: &str, decision: Decision) -> String {
+    format!(
+      "{}\0{}\0{}\0{}\0{}\0{}\0{}\0{}\0{}\0{}\0{}\0{}\0{}\0",
+      path,
+      new_path,
+      self.desc,
+      self.dexts.join(" "),
+      self.mime,
+      self.mexts.join(" "),
+      decision,
+      self.xexts.join(" "),
+      self.zmime,
+      self.charset,
+      self.sniffed.mime,
+      self.sniffed.detail,
+      self.interp
+    )
+  }
+}
+
+#[derive(Debug)]
+enum GenericType {
+  Description(String),
+  Mime(String),
+}
+
 #[derive(Debug)]
 enum MagicDatabase<'a> {
   File(&'a str),
-  Buffer(&'a [u8]),
+  Buffer(&'static [u8]),
 }
 
 #[derive(Debug, Default)]
@@ -73,15 +156,309 @@ struct Opts {
   detect:      bool,
   dump:        bool,
   nobuiltin:   bool,
+  preferext:   bool,
+  compress:    bool,
+  skipbintext: bool,
+  skipscripts: bool,
+  skipopen:    bool,
+  execbits:    bool,
+  cache:       bool,
+  mmap:        bool,
   matchinfo:   bool,
+  check:       bool,
+  audit:       bool,
+  quarantine:  Option<PathBuf>,
+  watch:       Option<PathBuf>,
   magicfile:   Option<String>,
-  extdot:      i32,
+  extdot:      Option<i32>,
   verbose:     bool,
+  include:     Vec<Pattern>,
+  exclude:     Vec<Pattern>,
+  allowlist:   Vec<Pattern>,
+  decisions:   bool,
+  only:        Vec<Decision>,
+  generic:     GenericPolicy,
+  langscore:   u32,
+  bytesmax:    Option<u64>,
+  stablefor:   u64,
+}
+
+impl Opts {
+  fn shows(&self, d: Decision) -> bool {
+    self.only.is_empty() || self.only.contains(&d)
+  }
+}
+
+// What to do with files whose type is generic, e.g. text/plain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum GenericPolicy {
+  Add,
+  Skip,
+  Rename,
+}
+
+impl Default for GenericPolicy {
+  fn default() -> GenericPolicy {
+    GenericPolicy::Add
+  }
+}
+
+impl FromStr for GenericPolicy {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<GenericPolicy, String> {
+    match s {
+      "add"    => Ok(GenericPolicy::Add),
+      "skip"   => Ok(GenericPolicy::Skip),
+      "rename" => Ok(GenericPolicy::Rename),
+      _        => Err(format!("Unknown generic type policy: {}", s)),
+    }
+  }
 }
 
 struct Types {
-  desc: Vec<(Regex, Vec<String>)>,
-  mime: HashMap<String, Vec<String>>,
+  desc: tables::Rules,
+  mime: tables::Map,
+  compound: tables::Table,
+  multi: &'static [&'static str],
+  generic_desc: RegexSet,
+  generic_mime: Vec<String>,
+  compat: tables::Table,
+  shebang: tables::Table,
+}
+
+impl Types {
+  // Extensions for a description/MIME pair, ignoring "?" matches the same way
+  // the main match does
+  fn lookup(&self, desc: &str, mime: &str) -> Option<tables::Exts> {
+    match self.desc.matches(desc).next() {
+      Some((_, ["?"], _)) | None => self.mime.get(mime),
+      Some((_, exts, _))         => Some(exts),
+    }
+  }
+
+  // Extensions for a MIME type with its charset, e.g. "text/plain;
+  // charset=utf-16le", falling back to the bare MIME type
+  fn mime_exts(&self, mime: &str, charset: &str) -> Option<(String, tables::Exts)> {
+    let with_charset = format!("{}; charset={}", mime, charset);
+
+    match self.mime.get(&with_charset) {
+      Some(exts) => Some((with_charset, exts)),
+      None       => self.mime.get(mime).map(|exts| (mime.to_string(), exts)),
+    }
+  }
+
+  // The longest known multi-part extension `basename` ends with
+  fn multi_ext(&self, basename: &str) -> Option<&str> {
+    self.multi.iter().copied().find(|e| ends_with_ext(basename, e))
+  }
+
+  // Whether `ext` is associated with a generic MIME type, e.g. "txt"
+  fn is_generic_ext(&self, ext: &str) -> bool {
+    self.generic_mime.iter().any(|g| self.mime.get(g).map_or(false, |exts| exts.contains(&ext)))
+  }
+
+  // Whether a match says little about the file: either its description is
+  // generic, or it was only matched by a generic MIME type, or it is a guess
+  // of what text looks like (e.g. CSV) for a file with a telling extension,
+  // or a source language libmagic did not see, whatever the extension
+  fn is_generic(&self, m: &MagicInfo, magic: &MagicMatch, ext: Option<&str>) -> bool {
+    let by_mime = matches!(magic, MagicMatch::Mime(..) | MagicMatch::Extension(_))
+      && self.generic_mime.contains(&normalize_mime(&m.mime));
+
+    let guessed = match magic {
+      MagicMatch::Mime(..)         => sniff::is_guess(&normalize_mime(&m.mime)),
+      MagicMatch::Sniffed(mime, _) => sniff::is_guess(mime),
+      _                            => false,
+    };
+
+    let by_guess = guessed && ext.map_or(false, |e| !self.is_generic_ext(e));
+
+    let by_lang = match magic {
+      MagicMatch::Sniffed(mime, _) => sniff::is_language(mime) && *mime != normalize_mime(&m.mime),
+      _                            => false,
+    };
+
+    by_mime || by_guess || by_lang || self.generic_desc.is_match(&m.desc)
+  }
+
+  // Whether the contents of a compressed file are generic, e.g. plain text
+  fn is_generic_inner(&self, m: &MagicInfo) -> bool {
+    self.generic_mime.contains(&normalize_mime(&m.zmime)) || self.generic_desc.is_match(&m.zdesc)
+  }
+
+  // Extensions for a script interpreter, ignoring a version suffix unless the
+  // versioned name is listed, e.g. "python3.11" falls back to "python"
+  fn shebang_exts(&self, interp: &str) -> Option<(String, tables::Exts)> {
+    let unversioned = interp.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.' || c == '-');
+
+    [interp, unversioned]
+      .iter()
+      .find_map(|i| tables::get(self.shebang, i).map(|exts| (i.to_string(), exts)))
+  }
+
+  // Whether `ext` is acceptable for the MIME type without being associated
+  // with it, e.g. "docx" for application/zip
+  fn is_compatible(&self, mime: &str, ext: &str) -> bool {
+    match tables::get(self.compat, &normalize_mime(mime)) {
+      Some(exts) => exts.iter().any(|e| *e == ext),
+      None       => false,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Decision {
+  Renamed,
+  AlreadyValid,
+  Mismatch,
+  Ignored,
+  Excluded,
+  Allowlisted,
+  Declined,
+  Directory,
+  Generic,
+  ExecScript,
+  InProgress,
+  Suspicious,
+  Unknown,
+  ExtdotOutOfBounds,
+  DestinationExists,
+  NotFound,
+  ReadFailed,
+  RenameFailed,
+  ChmodFailed,
+}
+
+impl Decision {
+  const ALL: [Decision; 19] = [
+    Decision::Renamed,
+    Decision::AlreadyValid,
+    Decision::Mismatch,
+    Decision::Ignored,
+    Decision::Excluded,
+    Decision::Allowlisted,
+    Decision::Declined,
+    Decision::Directory,
+    Decision::Generic,
+    Decision::ExecScript,
+    Decision::InProgress,
+    Decision::Suspicious,
+    Decision::Unknown,
+    Decision::ExtdotOutOfBounds,
+    Decision::DestinationExists,
+    Decision::NotFound,
+    Decision::ReadFailed,
+    Decision::RenameFailed,
+    Decision::ChmodFailed,
+  ];
+
+  fn name(self) -> &'static str {
+    match self {
+      Decision::Renamed           => "renamed",
+      Decision::AlreadyValid      => "already-valid",
+      Decision::Mismatch          => "mismatch",
+      Decision::Ignored           => "ignored",
+      Decision::Excluded          => "excluded",
+      Decision::Allowlisted       => "allowlisted",
+      Decision::Declined          => "declined",
+      Decision::Directory         => "directory",
+      Decision::Generic           => "generic",
+      Decision::ExecScript        => "exec-script",
+      Decision::InProgress        => "in-progress",
+      Decision::Suspicious        => "suspicious",
+      Decision::Unknown           => "unknown",
+      Decision::ExtdotOutOfBounds => "extdot-out-of-bounds",
+      Decision::DestinationExists => "destination-exists",
+      Decision::NotFound          => "not-found",
+      Decision::ReadFailed        => "read-failed",
+      Decision::RenameFailed      => "rename-failed",
+      Decision::ChmodFailed       => "chmod-failed",
+    }
+  }
+}
+
+impl fmt::Display for Decision {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str(self.name())
+  }
+}
+
+impl FromStr for Decision {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Decision, String> {
+    Decision::ALL
+      .iter()
+      .find(|d| d.name() == s)
+      .copied()
+      .ok_or_else(|| format!("Unknown decision: {}", s))
+  }
+}
+
+#[derive(Debug, Default)]
+struct Summary {
+  renamed:    usize,
+  valid:      usize,
+  unknown:    usize,
+  ignored:    usize,
+  collision:  usize,
+  mismatch:   usize,
+  suspicious: usize,
+  modes:      usize,
+  error:      usize,
+  // With -F and -I, decisions only say what would happen, so only files that
+  // could not be looked at count
+  informational: bool,
+}
+
+impl Summary {
+  fn add(&mut self, d: Decision) {
+    if self.informational {
+      if let Decision::NotFound | Decision::ReadFailed = d {
+        self.error += 1;
+      }
+
+      return;
+    }
+
+    match d {
+      Decision::Renamed           => self.renamed    += 1,
+      Decision::AlreadyValid      => self.valid      += 1,
+      Decision::Mismatch          => self.mismatch   += 1,
+      Decision::Suspicious        => self.suspicious += 1,
+      Decision::Ignored
+      | Decision::Excluded
+      | Decision::Allowlisted
+      | Decision::Declined
+      | Decision::Directory
+      | Decision::Generic
+      | Decision::ExecScript
+      | Decision::InProgress      => self.ignored    += 1,
+      Decision::Unknown           => self.unknown    += 1,
+      Decision::DestinationExists => self.collision  += 1,
+      Decision::ExtdotOutOfBounds
+      | Decision::NotFound
+      | Decision::ReadFailed
+      | Decision::RenameFailed
+      | Decision::ChmodFailed     => self.error      += 1,
+    }
+  }
+
+  fn exit_code(&self) -> i32 {
+    if self.error > 0 {
+      EXIT_ERROR
+    } else if self.renamed > 0
+      || self.collision > 0
+      || self.mismatch > 0
+      || self.suspicious > 0
+      || self.modes > 0
+    {
+      EXIT_FIXES
+    } else {
+      EXIT_OK
+    }
+  }
 }
 
 
@@ -128,12 +505,178 @@ fn path_to_dir_base(s: &path::Path) -> (String, String) {
   (dirname, basename)
 }
 
-fn magic_load(cookie: &Cookie, db: MagicDatabase) {
+fn path_matches_any(patterns: &[Pattern], p: &Path) -> bool {
+  let (_, basename) = path_to_dir_base(p);
+  let relative = p.strip_prefix(".").unwrap_or(p);
+
+  patterns.iter().any(|pat| {
+    pat.matches(&basename) || pat.matches_path(p) || pat.matches_path(relative)
+  })
+}
+
+#[cfg(unix)]
+fn is_executable(p: &Path) -> bool {
+  fs::metadata(p).map_or(false, |md| md.permissions().mode() & 0o111 != 0)
+}
+
+// There is no exec bit to go by
+#[cfg(not(unix))]
+fn is_executable(_p: &Path) -> bool {
+  false
+}
+
+fn validate_glob(optarg: String) -> Result<(), String> {
+  match Pattern::new(&optarg) {
+    Ok(_)  => Ok(()),
+    Err(e) => Err(format!("Invalid glob pattern '{}': {}", optarg, e)),
+  }
+}
+
+fn read_pattern_list(listfile: &str) -> Result<Vec<Pattern>, String> {
+  let contents = fs::read_to_string(listfile).map_err(|e| format!("{}: {}", listfile, e))?;
+  let mut result: Vec<Pattern> = vec![];
+
+  for line in contents.lines() {
+    let l = line.trim();
+
+    if l.is_empty() || l.starts_with('#') {
+      continue;
+    }
+
+    match Pattern::new(l) {
+      Ok(pat) => result.push(pat),
+      Err(e)  => return Err(format!("{}: invalid pattern '{}': {}", listfile, l, e)),
+    }
+  }
+
+  Ok(result)
+}
+
+fn validate_dir(optarg: String) -> Result<(), String> {
+  match fs::metadata(&optarg) {
+    Ok(md) if md.is_dir() => Ok(()),
+    Ok(_)                 => Err(format!("Not a directory: '{}'", optarg)),
+    Err(e)                => Err(format!("Cannot access '{}': {}", optarg, e)),
+  }
+}
+
+fn validate_readable(optarg: String) -> Result<(), String> {
+  match fs::File::open(&optarg) {
+    Ok(_)  => Ok(()),
+    Err(e) => Err(format!("Cannot read '{}': {}", optarg, e)),
+  }
+}
+
+// libmagic takes a list of magic files separated like $PATH, and looks for a
+// compiled "FILE.mgc" before FILE itself
+fn validate_magic_files(optarg: String) -> Result<(), String> {
+  for path in env::split_paths(&optarg) {
+    let compiled = PathBuf::from(format!("{}.mgc", path.to_string_lossy()));
+
+    if let Err(e) = fs::File::open(&compiled).or_else(|_| fs::File::open(&path)) {
+      return Err(format!("Cannot read '{}': {}", path.to_string_lossy(), e));
+    }
+  }
+
+  Ok(())
+}
+
+// MIME overrides may contain a charset parameter, which contains "=" itself,
+// so split those at the last "="
+fn parse_override(optarg: &str, last: bool) -> Result<(String, Vec<String>), String> {
+  let split = if last { optarg.rsplit_once('=') } else { optarg.split_once('=') };
+  let (key, exts) = split.ok_or_else(|| format!("Missing '=' in '{}'", optarg))?;
+
+  let exts: Vec<String> = exts
+    .split(|c: char| ", ".contains(c))
+    .filter(|s| !s.is_empty())
+    .map(|s| s.to_string())
+    .collect();
+
+  Ok((key.to_string(), exts))
+}
+
+// "text/plain;charset=binary" -> "text/plain; charset=binary"
+fn normalize_mime(mime: &str) -> String {
+  mime.split(';').map(str::trim).collect::<Vec<&str>>().join("; ")
+}
+
+fn validate_desc_override(optarg: String) -> Result<(), String> {
+  let (r, _) = parse_override(&optarg, false)?;
+
+  match Regex::new(&r) {
+    Ok(_)  => Ok(()),
+    Err(e) => Err(format!("Invalid regex '{}': {}", r, e)),
+  }
+}
+
+fn validate_mime_override(optarg: String) -> Result<(), String> {
+  let (m, _) = parse_override(&optarg, true)?;
+
+  if !m.split(';').next().unwrap_or_default().contains('/') {
+    return Err(format!("Invalid MIME '{}': no forward slash", m));
+  }
+
+  Ok(())
+}
+
+// MIME types are written as is, descriptions as /REGEX/
+fn parse_generic(optarg: &str) -> Result<GenericType, String> {
+  if optarg.len() > 1 && optarg.starts_with('/') && optarg.ends_with('/') {
+    let r = &optarg[1..optarg.len() - 1];
+
+    return match Regex::new(r) {
+      Ok(_)  => Ok(GenericType::Description(r.to_string())),
+      Err(e) => Err(format!("Invalid regex '{}': {}", r, e)),
+    };
+  }
+
+  if !optarg.contains('/') {
+    return Err(format!("Invalid MIME '{}': no forward slash", optarg));
+  }
+
+  Ok(GenericType::Mime(normalize_mime(optarg)))
+}
+
+fn validate_generic(optarg: String) -> Result<(), String> {
+  parse_generic(&optarg).map(|_| ())
+}
+
+// libmagic's own MAGIC_PARAM_BYTES_MAX is kept unless `bytes_max` is given
+fn magic_open(bytes_max: Option<u64>) -> Result<Cookie, String> {
+  macro_rules! open {
+    ($flags:expr) => {{
+      let cookie = libmagic::Cookie::open($flags).map_err(|e| {
+        format!("couldn't open a magic cookie with {}: {}", stringify!($flags), e)
+      })?;
+
+      if let Some(b) = bytes_max {
+        cookie
+          .set_bytes_max(b)
+          .map_err(|e| format!("couldn't set MAGIC_PARAM_BYTES_MAX to {}: {}", b, e))?;
+      }
+
+      cookie
+    }};
+  }
+
+  Ok(Cookie {
+    desc: open!(CookieFlags::default()),
+    mime: open!(CookieFlags::MIME_TYPE),
+    ext:  open!(CookieFlags::EXTENSION),
+    enc:  open!(CookieFlags::MIME_ENCODING),
+    zdesc: open!(CookieFlags::COMPRESS),
+    zmime: open!(CookieFlags::COMPRESS | CookieFlags::MIME_TYPE),
+  })
+}
+
+fn magic_load(cookie: &Cookie, db: MagicDatabase) -> Result<(), String> {
   #[allow(unused_macros)]
   macro_rules! cook {
     ($member:ident, $method:ident, $arg:ident, $error:expr) => {
-      cookie.$member.$method(&[&$arg]).expect(&*format!(
-        "Failed to initialize: {}: {}", stringify!($member), $error));
+      if let Err(e) = cookie.$member.$method($arg) {
+        return Err(format!("{}: {} ({})", stringify!($member), $error, e));
+      }
     };
   }
 
@@ -142,15 +685,77 @@ fn magic_load(cookie: &Cookie, db: MagicDatabase) {
     ($method:ident, $arg:ident, $error:expr) => {
       cook!(desc, $method, $arg, $error);
       cook!(mime, $method, $arg, $error);
+      cook!(ext,  $method, $arg, $error);
+      cook!(enc,  $method, $arg, $error);
+      cook!(zdesc, $method, $arg, $error);
+      cook!(zmime, $method, $arg, $error);
     };
   }
 
   match db {
     MagicDatabase::File(s)   => { load_both!(load,         s,
                                     format!("Invalid magic database: {}", s)); },
-    MagicDatabase::Buffer(b) => { load_both!(load_buffers, b,
+    MagicDatabase::Buffer(b) => { load_both!(load_buffer,  b,
                                     "Invalid built-in magic database"); },
   };
+
+  Ok(())
+}
+
+// Run a cookie on the buffer if there is one, or on the file
+macro_rules! magic_run {
+  ($cookie:expr, $path:expr, $buffer:expr) => {
+    match $buffer {
+      Some(b) => $cookie.buffer(b),
+      None    => $cookie.file($path),
+    }
+    .unwrap_or_default()
+  };
+}
+
+// What libmagic should look at instead of the file: the prefix read once, if
+// it is the whole file (libmagic may read further, and finds some formats at
+// the end) and not an ELF binary, whose details are read through the descriptor
+fn magic_buffer<'a>(desc: &str, prefix: Option<&'a prefix::Prefix>) -> Option<&'a [u8]> {
+  prefix.filter(|p| p.whole && !desc.starts_with("ELF ")).map(|p| &p[..])
+}
+
+// Run the cookies on `prefix`, the prefix of the file at `path`, or on the
+// file itself where libmagic needs it: special and empty files have no prefix
+// and are told by their metadata
+fn magic_detect(c: &Cookie, path: &Path, prefix: Option<&prefix::Prefix>) -> cache::Detected {
+  let desc = prefix
+    .filter(|p| p.whole)
+    .map(|b| c.desc.buffer(b).unwrap_or_default())
+    .unwrap_or_default();
+  let buffer = magic_buffer(&desc, prefix);
+
+  let desc = match buffer {
+    Some(_) => desc,
+    None    => c.desc.file(path).unwrap_or_default(),
+  };
+
+  cache::Detected {
+    desc,
+    mime:    magic_run!(c.mime, path, buffer),
+    ext:     magic_run!(c.ext, path, buffer),
+    charset: magic_run!(c.enc, path, buffer),
+    ..Default::default()
+  }
+}
+
+// MAGIC_EXTENSION output is a slash-separated list, or "???" if unknown
+fn magic_exts(s: &str) -> Vec<String> {
+  s.split('/')
+    .filter(|e| !e.is_empty() && *e != "???")
+    .map(String::from)
+    .collect()
+}
+
+fn ends_with_ext(basename: &str, ext: &str) -> bool {
+  basename.len() > ext.len() + 1
+    && basename.ends_with(ext)
+    && basename[..basename.len() - ext.len()].ends_with('.')
 }
 
 fn vec_si<T>(v: &[T], i: i32) -> Option<&[T]> {
@@ -173,28 +778,26 @@ fn vec_si<T>(v: &[T], i: i32) -> Option<&[T]> {
   return Some(&v[idx..]);
 }
 
-fn visit_tree<OkT>(
+fn visit_tree<T>(
   t: &Path,
-  fv: &dyn Fn(PathBuf) -> Result<OkT, String>,
-  dv: &dyn Fn(PathBuf) -> Result<OkT, String>,
-  ev: &dyn Fn(PathBuf, String),
-) where
-  OkT: Clone,
-{
+  fv: &dyn Fn(PathBuf) -> T,
+  dv: &dyn Fn(PathBuf) -> Result<(), T>,
+  ev: &dyn Fn(PathBuf, String) -> T,
+  rv: &mut dyn FnMut(T),
+) {
   let metadata_result = fs::metadata(&t);
 
   if let Err(e) = metadata_result {
     let estr = e.to_string();
-    (ev)(t.to_path_buf(), estr);
+    (rv)((ev)(t.to_path_buf(), estr));
     return;
   }
 
   let metadata = metadata_result.unwrap();
 
   if metadata.is_dir() {
-    let dir_result = (dv)(t.to_path_buf());
-
-    if dir_result.is_err() {
+    if let Err(r) = (dv)(t.to_path_buf()) {
+      (rv)(r);
       return;
     }
 
@@ -202,7 +805,7 @@ fn visit_tree<OkT>(
 
     if let Err(e) = rd {
       let estr = e.to_string();
-      (ev)(t.to_path_buf(), estr);
+      (rv)((ev)(t.to_path_buf(), estr));
       return;
     }
 
@@ -212,19 +815,36 @@ fn visit_tree<OkT>(
       match entry {
         Err(e) => {
           let estr = e.to_string();
-          (ev)(t.to_path_buf(), estr.clone());
+          (rv)((ev)(t.to_path_buf(), estr));
         }
 
         Ok(de) => {
-          visit_tree(&de.path(), fv, dv, ev);
+          visit_tree(&de.path(), fv, dv, ev, rv);
         }
       }
     }
   } else {
-    let _ = (fv)(t.to_path_buf());
+    (rv)((fv)(t.to_path_buf()));
   };
 }
 
+// Whether the user answers yes to `prompt`; exits on ^C
+fn confirm(prompt: &str) -> bool {
+  let mut rl = Editor::<()>::new();
+
+  match rl.readline(prompt) {
+    Ok(line) => {
+      let yes: Regex = Regex::new(r"^\s*[yY]").unwrap();
+      yes.is_match(&*line)
+    }
+    Err(ReadlineError::Interrupted) => {
+      eprintln!("Received an interrupt");
+      process::exit(130);
+    }
+    _ => false,
+  }
+}
+
 fn quote_filename(filename: &str) -> String {
   if cfg!(not(windows)) {
     return shellwords::escape(filename);
@@ -241,22 +861,66 @@ fn main() {
     (version: VERSION.unwrap_or("VERSION"))
     (author:  AUTHORS.unwrap_or("AUTHOR"))
     (about:   DESCRIP.unwrap_or("DESCRIPTION"))
-    (@arg FILE: ... required_unless[dump]
+    (@arg FILE: ... required_unless[dump watch]
                           "Files to check/rename")
     (@arg dry:         -n ... "Dry run: do not actually rename FILEs")
     (@arg interactive: -i ... "Prompt before renaming files")
     (@arg force:       -f ... "When non-interactive, overwrite existing destinations")
     (@arg recursive:   -r ... "Recurse into directory FILEs instead of ignoring")
     (@arg append:      -A ... "Append the correct extension instead of replacing")
-    (@arg nobuiltin:   -B ... "Do not use built-in extension associations")
+    (@arg nobuiltin:   -B ... "Do not use built-in extension associations and generic types")
+    (@arg preferext:   -E ... "Prefer extensions suggested by libmagic over associations")
+    (@arg compress:    -z ... "Look inside compressed files and use compound extensions")
+    (@arg skipbintext: --skip-binary-text
+                              "Skip text/* files whose encoding libmagic reports as binary")
+    (@arg skipscripts: --("skip-exec-scripts")
+                              "Skip executable scripts without an extension")
+    (@arg skipopen:    --("skip-open")
+                              "Skip files open for writing by other processes (Linux only)")
+    (@arg stablefor:   --("stable-for") [SECS]
+                          !empty_values
+      {
+        |optarg| match optarg.parse::<u64>() {
+          Ok(_)  => Ok(()),
+          Err(_) => Err(format!("Not a number of seconds: {}", optarg))
+        }
+      }
+      "Skip files that change within SECS of their last modification [default: 5 with --watch]")
+    (@arg cache:       --cache
+                              "Cache detected types of unchanged files across runs")
+    (@arg mmap:        --mmap conflicts_with[watch]
+                              "Map files into memory instead of reading them")
+    (@arg execbits:    -x --("exec-bits")
+                              "Also set or clear exec bits to match the file type")
     (@arg detect:      -F ... group("action")
                               "Only print detected types (like `file --mime-type`)")
     (@arg dump:        -D ... group("action")
                               "Print known descriptions/MIME types and associated extensions")
     (@arg matchinfo:   -I ... group("action")
                               "Output null-separated match info")
+    (@arg check:       -C --check group("action")
+                              "Only report files with mismatched extensions, never rename")
+    (@arg audit:       --audit group("action")
+                              "Only report files disguised as another type, never rename")
+    (@arg quarantine:  --quarantine [DIR] requires[audit]
+                          !empty_values {validate_dir}
+      "With --audit, move suspicious files into DIR")
+    (@arg watch:       --watch [DIR]
+                          !empty_values {validate_dir}
+      "After FILEs, keep fixing files written or moved into DIR (implies -d)")
+    (@arg decisions:   -d --decisions
+                              "Prefix each output line with the decision made for the file")
+    (@arg only:        --only [DECISIONS] ... number_of_values(1)
+                          !empty_values
+      {
+        |optarg| match optarg.split(',').map(Decision::from_str).find(Result::is_err) {
+          Some(Err(e)) => Err(e),
+          _            => Ok(())
+        }
+      }
+      "Only output files with one of the comma-separated DECISIONS")
     (@arg magicfile:   -M [MGC]
-                          !empty_values +allow_hyphen_values
+                          !empty_values +allow_hyphen_values {validate_magic_files}
                               "Load magic definitions from MGC")
     (@arg extdot:      -L [IDX]
                           !empty_values +allow_hyphen_values
@@ -268,16 +932,82 @@ fn main() {
       }
       "Cut off the extension after the IDX-th dot.")
     (@arg ovdesc:      -Z [DESC_OVERRIDE] ... number_of_values(1)
-                          !empty_values
+                          !empty_values {validate_desc_override}
       "(in form TYPE=EXTS) Override EXTS for files matching description TYPE")
     (@arg ovmime:      -X [MIME_OVERRIDE] ... number_of_values(1)
-                          !empty_values
+                          !empty_values {validate_mime_override}
       "(in form MIME=EXTS) Override EXTS for files matching MIME")
+    (@arg include:     --include [GLOB] ... number_of_values(1)
+                          !empty_values {validate_glob}
+      "Only process files whose name or path matches GLOB")
+    (@arg exclude:     --exclude [GLOB] ... number_of_values(1)
+                          !empty_values {validate_glob}
+      "Skip files and directories whose name or path matches GLOB")
+    (@arg generictype: -G [GENERIC] ... number_of_values(1)
+                          !empty_values {validate_generic}
+      "(MIME or /DESC/) Treat files matching GENERIC as a generic type")
+    (@arg generic:     --generic [POLICY] possible_values(&["add", "skip", "rename"])
+      "What to do with files of a generic type [default: add]")
+    (@arg langscore:   --("lang-threshold") [SCORE]
+                          !empty_values
+      {
+        |optarg| match optarg.parse::<u32>() {
+          Ok(s) if s <= 100 => Ok(()),
+          _                 => Err(format!("Not a score from 0 to 100: {}", optarg))
+        }
+      }
+      "Minimum confidence to trust a guessed source language [default: 60]")
+    (@arg bytesmax:    --("bytes-max") [BYTES]
+                          !empty_values
+      {
+        |optarg| match optarg.parse::<u64>() {
+          Ok(b) if b > 0 => Ok(()),
+          _              => Err(format!("Not a positive number of bytes: {}", optarg))
+        }
+      }
+      "How much of each file to read, and libmagic's limit [default: 1048576, libmagic's own]")
+    (@arg allowlist:   --allowlist [LIST]
+                          !empty_values {validate_readable}
+      "With -C, accept mismatches in files matching any GLOB listed in LIST")
     (@arg verbose:     -v --verbose
        "Show additional information about matched file magic"))
   .setting(clap::AppSettings::DeriveDisplayOrder);
 
-  let matches = app.get_matches();
+  macro_rules! message {
+    ($fmt:expr, $($arg:tt)*) => {
+      eprint!("{}: ", bold("fixext"));
+      eprintln!($fmt, $($arg)*);
+    };
+  }
+
+  macro_rules! message_path {
+    ($file:expr, $fmt:expr, $($arg:tt)*) => {
+      message!(concat!($fmt, " {}"), $($arg)*, $file);
+    };
+  }
+
+  macro_rules! verbose_path {
+    ($o:expr, $file:expr, $fmt:expr, $($arg:tt)*) => {
+      if $o.verbose {
+        message_path!($file, $fmt, $($arg)*);
+      }
+    };
+  }
+
+  macro_rules! bold_format {
+    ($fmt:expr, $($arg:tt)*) => {
+      bold(&*format!($fmt, $($arg)*))
+    };
+  }
+
+  let matches = match app.get_matches_safe() {
+    Ok(m) => m,
+    Err(e) if e.use_stderr() => {
+      eprintln!("{}", e.message);
+      process::exit(EXIT_USAGE);
+    }
+    Err(e) => e.exit(),
+  };
   let files = matches.values_of("FILE").unwrap_or_default();
 
   let o: Opts = {
@@ -295,56 +1025,89 @@ fn main() {
     get_flag!(recursive);
     get_flag!(append);
     get_flag!(nobuiltin);
+    get_flag!(preferext);
+    get_flag!(compress);
+    get_flag!(skipbintext);
+    get_flag!(skipscripts);
+    get_flag!(skipopen);
+    get_flag!(audit);
+    get_flag!(execbits);
+    get_flag!(cache);
+    get_flag!(mmap);
     get_flag!(detect);
     get_flag!(dump);
     get_flag!(matchinfo);
+    get_flag!(check);
+    get_flag!(decisions);
     get_flag!(verbose);
 
-    o.extdot = match matches.value_of("extdot") {
-      Some(v)  => v.parse::<i32>().unwrap(),
-      None     => -1
-    };
+    o.extdot = matches.value_of("extdot").map(|v| v.parse::<i32>().unwrap());
 
     o.magicfile = matches.value_of("magicfile").map(String::from);
 
-    o
-  };
+    macro_rules! get_patterns {
+      ($var:ident) => {
+        o.$var = matches
+          .values_of(stringify!($var))
+          .unwrap_or_default()
+          .map(|g| Pattern::new(g).unwrap())
+          .collect();
+      };
+    }
 
-  let (builtin_desc_types, builtin_mime_types_vec, builtin_mime_types) = if !o.nobuiltin {
-    let builtin_desc_types: Vec<(Regex, Vec<String>)> =
-      serde_cbor::from_slice::<Vec<(String, Vec<String>)>>(DESC_TYPES_CBOR)
-        .expect("Failed to initialize: invalid built-in desc.types CBOR")
-        .iter()
-        .map(|d| {
-          let (r, exts) = d;
-          let regex = Regex::new(&*r).expect(&*format!(
-            "Failed to initialize: invalid regex in description CBOR: {}",
-            r
-          ));
-          return (regex, exts.clone());
-        })
-        .collect();
-
-    let builtin_mime_types_vec: Vec<(String, Vec<String>)> =
-      serde_cbor::from_slice::<Vec<(String, Vec<String>)>>(MIME_TYPES_CBOR)
-        .expect("Failed to initialize: invalid built-in mime.types CBOR");
-
-    let builtin_mime_types: HashMap<String, Vec<String>> = {
-      let mut mt = HashMap::new();
-      mt.extend(builtin_mime_types_vec.clone());
-      mt
-    };
+    get_patterns!(include);
+    get_patterns!(exclude);
 
-    (builtin_desc_types, builtin_mime_types_vec, builtin_mime_types)
-  } else {
-    (Vec::new(), Vec::new(), HashMap::new())
+    o.only = matches
+      .values_of("only")
+      .unwrap_or_default()
+      .flat_map(|v| v.split(','))
+      .map(|d| Decision::from_str(d).unwrap())
+      .collect();
+
+    o.generic = matches
+      .value_of("generic")
+      .map(|p| GenericPolicy::from_str(p).unwrap())
+      .unwrap_or_default();
+
+    o.quarantine = matches.value_of("quarantine").map(PathBuf::from);
+    o.watch = matches.value_of("watch").map(PathBuf::from);
+
+    o.stablefor = matches
+      .value_of("stablefor")
+      .map_or(if o.watch.is_some() { DEFAULT_WATCH_STABLE_FOR } else { 0 }, |s| s.parse().unwrap());
+
+    // A log of what happened to each file
+    o.decisions |= o.watch.is_some();
+
+    o.langscore = matches
+      .value_of("langscore")
+      .map_or(DEFAULT_LANG_THRESHOLD, |s| s.parse().unwrap());
+
+    o.bytesmax = matches.value_of("bytesmax").map(|b| b.parse().unwrap());
+
+    if let Some(listfile) = matches.value_of("allowlist") {
+      o.allowlist = match read_pattern_list(listfile) {
+        Ok(l)  => l,
+        Err(e) => {
+          message!("{} {}", bold("Failed to read the allowlist:"), e);
+          process::exit(EXIT_USAGE);
+        }
+      };
+    }
+
+    o
   };
 
-  let c = Cookie {
-    desc: magic::Cookie::open(CookieFlags::default())
-      .expect("Failed to initialize: couldn't open a magic cookie with default flags"),
-    mime: magic::Cookie::open(CookieFlags::MIME_TYPE)
-      .expect("Failed to initialize: couldn't open a magic cookie with MAGIC_MIME_TYPE"),
+  // With -B, only overrides are known
+  let builtin = |table: tables::Table| if o.nobuiltin { &[][..] } else { table };
+
+  let c = match magic_open(o.bytesmax) {
+    Ok(c)  => c,
+    Err(e) => {
+      message!("{} {}", bold("Failed to initialize:"), e);
+      process::exit(EXIT_ERROR);
+    }
   };
 
   let init_mgc: MagicDatabase = match &o.magicfile {
@@ -358,156 +1121,469 @@ fn main() {
     }
   };
 
-  magic_load(&c, init_mgc);
-
-  macro_rules! message {
-    ($fmt:expr, $($arg:tt)*) => {
-      eprint!("{}: ", bold("fixext"));
-      eprintln!($fmt, $($arg)*);
-    };
+  if let Err(e) = magic_load(&c, init_mgc) {
+    message!("{} {}", bold("Failed to initialize:"), e);
+    process::exit(if o.magicfile.is_some() { EXIT_USAGE } else { EXIT_ERROR });
   }
 
-  macro_rules! message_path {
-    ($file:expr, $fmt:expr, $($arg:tt)*) => {
-      message!(concat!($fmt, " {}"), $($arg)*, $file);
-    };
-  }
-
-  macro_rules! verbose_path {
-    ($o:expr, $file:expr, $fmt:expr, $($arg:tt)*) => {
-      if $o.verbose {
-        message_path!($file, $fmt, $($arg)*);
-      }
-    };
-  }
-
-  macro_rules! bold_format {
-    ($fmt:expr, $($arg:tt)*) => {
-      bold(&*format!($fmt, $($arg)*))
-    };
-  }
-
-  let desc_types: Vec<(Regex, Vec<String>)> = {
-    let mut result: Vec<(Regex, Vec<String>)> = vec![];
+  let desc_types: tables::Rules = {
+    let mut overrides: Vec<(String, tables::Exts)> = vec![];
 
     match matches.values_of("ovdesc") {
       None    => (),
       Some(o) => o.for_each(|d| {
-        let splits: Vec<String> = d.splitn(2, '=').map(|s| s.to_string()).collect();
-        let r: &String = &splits[0];
-        let exts: Vec<String> = splits[1]
-          .split(|c: char| ", ".contains(c))
-          .filter(|s| !s.is_empty())
-          .map(|s| s.to_string())
-          .collect();
-
-        match Regex::new(r) {
-          Ok(regex) => result.push((regex, exts)),
-          Err(e)    => panic!("Invalid regex in option '-Z{}': {}", r, e)
-        }
+        // Both are checked by validate_desc_override()
+        let (r, exts) = parse_override(d, false).unwrap();
+        overrides.push((r, tables::leak(exts)));
       }),
     };
 
-    result.reverse();
-    [&result[..], &builtin_desc_types[..]].concat()
-  };
+    overrides.reverse();
 
-  let mime_types: HashMap<String, Vec<String>> = {
-    let mut result: HashMap<String, Vec<String>> = HashMap::new();
+    let overrides: Vec<(&str, tables::Exts)> =
+      overrides.iter().map(|(r, exts)| (r.as_str(), *exts)).collect();
 
-    result.extend(builtin_mime_types);
+    tables::Rules::new(&overrides, builtin(tables::DESC_TYPES))
+      .expect("Failed to initialize: invalid description regex")
+  };
+
+  let mime_types: tables::Map = {
+    let mut overrides: HashMap<String, tables::Exts> = HashMap::new();
 
     match matches.values_of("ovmime") {
       None    => (),
       Some(o) => o.for_each(|m| {
-        let splits: Vec<String> = m.splitn(2, '=').map(|s| s.to_string()).collect();
-        let m: &String = &splits[0];
-        let exts: Vec<String> = splits[1]
-          .split(|c: char| ", ".contains(c))
-          .filter(|s| !s.is_empty())
-          .map(|s| s.to_string())
-          .collect();
-
-        assert!(m.contains('/'), "Invalid MIME in option '-X{}': no forward slash", m);
-
-        result.insert(m.clone(), exts);
+        // Checked by validate_mime_override()
+        let (m, exts) = parse_override(m, true).unwrap();
+        overrides.insert(normalize_mime(&m), tables::leak(exts));
       }),
     };
 
-    result
+    tables::Map::new(builtin(tables::MIME_TYPES), overrides)
+  };
+
+  let (generic_desc, generic_mime): (RegexSet, Vec<String>) = {
+    let mut generic_desc: Vec<String> = vec![];
+    let mut generic_mime: Vec<String> = vec![];
+
+    if !o.nobuiltin {
+      generic_desc.extend(tables::GENERIC_DESCS.iter().map(|d| d.to_string()));
+      generic_mime.extend(tables::GENERIC_MIMES.iter().map(|m| m.to_string()));
+    }
+
+    // Checked by validate_generic()
+    matches
+      .values_of("generictype")
+      .unwrap_or_default()
+      .map(|g| parse_generic(g).unwrap())
+      .for_each(|g| match g {
+        GenericType::Description(r) => generic_desc.push(r),
+        GenericType::Mime(m)        => generic_mime.push(m),
+      });
+
+    let generic_desc = RegexSet::new(&generic_desc)
+      .expect("Failed to initialize: invalid generic description regex");
+
+    (generic_desc, generic_mime)
   };
 
   let types: Types = Types {
     desc: desc_types,
     mime: mime_types,
+    compound: tables::COMPOUND_TYPES,
+    multi: tables::MULTI_EXTS,
+    generic_desc,
+    generic_mime,
+    compat: builtin(tables::COMPAT_TYPES),
+    shebang: builtin(tables::SHEBANG_TYPES),
+  };
+
+  let auditor: Option<audit::Auditor> = if o.audit {
+    Some(audit::Auditor::new(&types))
+  } else {
+    None
   };
 
   if o.dump {
-    builtin_desc_types.iter().for_each(|(r, exts)| {
-      println!("{}\t{}", r, exts.join(" "));
-    });
+    let print_table = |table: tables::Table, separator: &str| {
+      table.iter().for_each(|(key, exts)| {
+        println!("{}{}{}", key, separator, exts.join(" "));
+      });
+
+      println!("__END__");
+    };
+
+    print_table(builtin(tables::DESC_TYPES), "\t");
+    print_table(builtin(tables::MIME_TYPES), " ");
+    print_table(tables::COMPOUND_TYPES, " ");
+
+    if !o.nobuiltin {
+      tables::GENERIC_MIMES.iter().for_each(|generic| println!("{}", generic));
+      tables::GENERIC_DESCS.iter().for_each(|generic| println!("/{}/", generic));
+    }
 
     println!("__END__");
 
-    builtin_mime_types_vec.iter().for_each(|(m, exts)| {
-      println!("{} {}", m, exts.join(" "));
+    print_table(builtin(tables::COMPAT_TYPES), " ");
+
+    builtin(tables::SHEBANG_TYPES).iter().for_each(|(interp, exts)| {
+      println!("{} {}", interp, exts.join(" "));
     });
 
     return;
   }
 
-  let file_visitor: &dyn Fn(PathBuf) -> Result<(), String> = &|path| {
+  macro_rules! decide {
+    ($path_str:expr, $decision:expr) => {
+      decide!($path_str, $decision, None)
+    };
+    ($path_str:expr, $decision:expr, $line:expr) => {{
+      let decision: Decision = $decision;
+      let line: Option<String> = $line;
+
+      if o.shows(decision) {
+        match (line, o.decisions && !o.matchinfo) {
+          (Some(l), true)  => println!("{}\t{}", decision, l),
+          (Some(l), false) => println!("{}", l),
+          (None,    true)  => println!("{}\t{}", decision, $path_str),
+          (None,    false) => (),
+        }
+      }
+
+      decision
+    }};
+  }
+
+  // Exec bit changes are made alongside the decision for each file
+  let modes_changed: Cell<usize> = Cell::new(0);
+
+  // Taken when first needed, and again for each batch of watched files
+  let writers: RefCell<Option<inprogress::Writers>> = RefCell::new(None);
+
+  // Taken before visiting each batch of files, with --stable-for
+  let unstable: RefCell<Option<inprogress::Unstable>> = RefCell::new(None);
+
+  // Where files were renamed to, so that watching does not pick them up again
+  let renamed_to: RefCell<Vec<PathBuf>> = RefCell::new(vec![]);
+
+  let cache: Option<RefCell<cache::Cache>> = match (o.cache, cache::default_path()) {
+    (true, Some(path)) => {
+      let magic = match &o.magicfile {
+        Some(p)                => Some(p.as_str()),
+        None if cfg!(windows)  => None,
+        None                   => Some(DEFAULT_MGC.unwrap_or("/usr/share/misc/magic.mgc")),
+      };
+
+      let options = format!("lang-threshold {}, bytes-max {:?}", o.langscore, o.bytesmax);
+      let identity = cache::identity(magic, VERSION.unwrap_or("VERSION"), &options);
+
+      Some(RefCell::new(cache::Cache::open(path, identity)))
+    }
+    (true, None) => {
+      message!("{}", bold("Cannot find a cache directory, not caching"));
+      None
+    }
+    (false, _) => None,
+  };
+
+  let file_visitor: &dyn Fn(PathBuf) -> Decision = &|path| {
     let path_str = path.as_os_str().to_string_lossy().into_owned();
 
     if !path.exists() {
       message!("{} {}", bold("ERROR: File does not exist, skipping:"), path_str);
-      return Err("file does not exist".to_string());
+      return decide!(path_str, Decision::NotFound);
+    }
+
+    if path_matches_any(&o.exclude, &path)
+      || (!o.include.is_empty() && !path_matches_any(&o.include, &path))
+    {
+      verbose_path!(o, path_str, "{}", bold("File excluded, skipping:"));
+      return decide!(path_str, Decision::Excluded);
+    }
+
+    // Only reports of detected types are safe while files are being written
+    if !o.detect && !o.matchinfo {
+      let basename = path.file_name().unwrap_or_default().to_string_lossy();
+
+      let reason: Option<String> = if let Some(r) = inprogress::partial_name(&path, &basename) {
+        Some(r)
+      } else if o.skipopen
+        && writers.borrow_mut().get_or_insert_with(inprogress::Writers::scan).contains(&path)
+      {
+        Some(String::from("open for writing"))
+      } else if unstable.borrow().as_ref().map_or(false, |u| u.contains(&path)) {
+        Some(format!("changed within {} seconds", o.stablefor))
+      } else {
+        None
+      };
+
+      if let Some(r) = reason {
+        verbose_path!(o, path_str, "{}", bold_format!("File is being written ({}), skipping:", r));
+        return decide!(path_str, Decision::InProgress);
+      }
     }
 
-    let (desc, mime, magic, dexts, mexts): (String, String, MagicMatch, Vec<String>, Vec<String>) = 'magic: {
-      let desc = c.desc.file(&path).unwrap_or_default();
-      let mime = c.mime.file(&path).unwrap_or_default();
-      let mut dexts: Vec<String> = vec![];
-      let mut mexts: Vec<String> = vec![];
+    let (m, magic): (MagicInfo, MagicMatch) = 'magic: {
+      let cached: Option<cache::Detected> = cache.as_ref().and_then(|c| c.borrow_mut().get(&path));
+
+      // Only read once something is not cached
+      let mut head: Option<Option<prefix::Prefix>> = None;
+
+      macro_rules! head {
+        () => {
+          head
+            .get_or_insert_with(|| {
+              prefix::read(&path, o.bytesmax.unwrap_or(DEFAULT_PREFIX_LEN), o.mmap).ok().flatten()
+            })
+            .as_ref()
+        };
+      }
+
+      macro_rules! content {
+        () => {
+          head!().and_then(|b| sniff::Content::new(&path, &b[..]).ok())
+        };
+      }
+
+      let mut d: cache::Detected =
+        cached.clone().unwrap_or_else(|| magic_detect(&c, &path, head!()));
+
+      let mut m = MagicInfo {
+        desc:  d.desc.clone(),
+        mime:  d.mime.clone(),
+        xexts: magic_exts(&d.ext),
+        charset: d.charset.clone(),
+        ..Default::default()
+      };
 
       let mut result: MagicMatch = MagicMatch::None;
 
-      if desc == mime && mime == String::default() {
-        break 'magic (desc, mime, result, dexts, mexts);
+      if m.desc == m.mime && m.mime == String::default() {
+        break 'magic (m, result);
       }
 
-      for (r, exts) in &types.desc {
-        if r.is_match(&*desc) {
-          dexts = exts.clone();
+      // Whether the match is a -Z or -X override, which the interpreter or
+      // sniffed content of the file does not change
+      let mut overridden = false;
+
+      let mut described: Option<(&str, tables::Exts, bool)> = None;
+      let mut dexts: tables::Exts = &[];
+
+      for (r, exts, user) in types.desc.matches(&m.desc) {
+        dexts = exts;
+
+        if exts == ["?"] {
+          verbose_path!(
+            o,
+            path_str,
+            "{}",
+            bold_format!(
+              "File description \"{}\" matches /{}/, extensions {:?}, is ignored:",
+              m.desc,
+              r,
+              exts
+            )
+          );
+          break;
+        }
 
-          if *exts == vec![String::from("?")] {
-            verbose_path!(
-              o,
-              path_str,
-              "{}",
-              bold_format!(
-                "File description \"{}\" matches /{}/, extensions {:?}, is ignored:",
-                desc,
-                r,
-                exts
-              )
-            );
-            break;
+        described = Some((r, exts, user));
+      }
+
+      m.dexts = tables::owned(dexts);
+
+      if let Some((r, exts, user)) = described {
+        result = MagicMatch::Description(r.to_string(), tables::owned(exts));
+        overridden = user;
+      }
+
+      if let (Some((key, exts)), MagicMatch::None) = (types.mime_exts(&m.mime, &m.charset), &result) {
+        m.mexts = tables::owned(exts);
+        overridden = types.mime.is_override(&key);
+        result = MagicMatch::Mime(key, m.mexts.clone());
+      }
+
+      // Only fall back to libmagic's own suggestions with -B if asked to,
+      // and never override an explicit "*"
+      let ignored = matches!(
+        &result,
+        MagicMatch::Description(_, exts) | MagicMatch::Mime(_, exts) if *exts == vec!["*"]
+      );
+
+      let fallback = matches!(result, MagicMatch::None) && !o.nobuiltin;
+
+      if !m.xexts.is_empty() && ((o.preferext && !ignored) || fallback) {
+        result = MagicMatch::Extension(m.xexts.clone());
+        overridden = false;
+      }
+
+      // Scripts name their interpreter, which beats any guess from the content
+      if !ignored {
+        m.interp = d
+          .interp
+          .get_or_insert_with(|| {
+            content!().and_then(|t| sniff::interpreter(&t)).unwrap_or_default()
+          })
+          .clone();
+
+        if let (Some((key, exts)), false) = (types.shebang_exts(&m.interp), overridden) {
+          result = MagicMatch::Shebang(key, tables::owned(exts));
+        }
+      }
+
+      // Look inside containers libmagic could not tell apart
+      if !ignored && !overridden && !matches!(result, MagicMatch::Shebang(..)) {
+        let sniffed = d
+          .sniffed
+          .get_or_insert_with(|| content!().and_then(|t| sniff::refine(&t, &m.mime, o.langscore)));
+
+        if let Some(s) = sniffed.clone() {
+          if let Some(exts) = types.mime.get(&normalize_mime(&s.mime)) {
+            result = MagicMatch::Sniffed(s.mime.clone(), tables::owned(exts));
           }
 
-          result = MagicMatch::Description(r.clone(), exts.clone());
+          m.sniffed = s;
+        }
+      }
+
+      if o.compress && !ignored {
+        let desc = &m.desc;
+
+        m.zdesc = d
+          .zdesc
+          .get_or_insert_with(|| magic_run!(c.zdesc, &path, magic_buffer(desc, head!())))
+          .clone();
+        m.zmime = d
+          .zmime
+          .get_or_insert_with(|| magic_run!(c.zmime, &path, magic_buffer(desc, head!())))
+          .clone();
+
+        let outer: Option<&[String]> = match &result {
+          MagicMatch::Description(_, exts)
+          | MagicMatch::Mime(_, exts)
+          | MagicMatch::Sniffed(_, exts)
+          | MagicMatch::Shebang(_, exts)
+          | MagicMatch::Extension(exts) => Some(exts),
+          _                             => None,
+        };
+
+        let inner: Option<tables::Exts> = if m.zmime != m.mime {
+          types.lookup(&m.zdesc, &m.zmime)
+        } else {
+          None
+        };
+
+        // Generic contents add nothing to the outer extension, e.g. logs.gz
+        let generic_inner = o.generic != GenericPolicy::Rename && types.is_generic_inner(&m);
+        let inner = inner.filter(|exts| !exts.is_empty() && **exts != ["*"] && !generic_inner);
+        let outer = outer.filter(|exts| !exts.is_empty() && **exts != ["*"]);
+
+        if let (Some(inner), Some(outer)) = (inner, outer) {
+          let compound = format!("{}.{}", inner[0], outer[0]);
+          let exts = match tables::get(types.compound, &compound) {
+            Some(exts) => tables::owned(exts),
+            None       => vec![compound],
+          };
+
+          result = MagicMatch::Compressed(m.zmime.clone(), exts);
+        }
+      }
+
+      if let Some(cache) = &cache {
+        if cached.as_ref() != Some(&d) {
+          cache.borrow_mut().put(&path, d);
         }
       }
 
-      if let (Some(exts), MagicMatch::None) = (types.mime.get(&mime), &result) {
-        result = MagicMatch::Mime(mime.clone(), exts.clone());
-        mexts = exts.clone();
+      (m, result)
+    };
+
+    let generic = types.is_generic(&m, &magic, path.extension().and_then(|e| e.to_str()));
+
+    // The most precise MIME type known: a sniffed one, or libmagic's
+    let compat_mime = match &magic {
+      MagicMatch::Sniffed(mime, _) => mime.clone(),
+      _                            => m.mime.clone(),
+    };
+
+    // Exec bits follow the type, whatever happens to the name
+    let chmod: Option<(u32, u32)> = if o.execbits && !o.detect && !o.matchinfo && !o.audit {
+      match (modes::wants_exec(&m, &compat_mime), modes::mode(&path)) {
+        (Some(exec), Ok(Some(mode))) if modes::has_exec(mode) != exec => {
+          Some((mode, modes::reconcile(mode, exec)))
+        }
+        _ => None,
+      }
+    } else {
+      None
+    };
+
+    // Made once the name is decided, before any rename, so that -i and --only
+    // apply to it like to the rename itself
+    let fix_mode = |decision: Decision| -> Decision {
+      let (mode, new_mode) = match chmod {
+        Some(chmod) => chmod,
+        None        => return decision,
+      };
+
+      let line = if o.check {
+        format!(
+          "{}: expected mode {:04o} ({}), found {:04o}",
+          path_str,
+          new_mode,
+          compat_mime,
+          mode
+        )
+      } else {
+        format!(
+          "{}{}: mode {:04o} -> {:04o}",
+          if o.dry { "(DRY RUN) " } else { "" },
+          quote_filename(&path_str),
+          mode,
+          new_mode
+        )
+      };
+
+      if o.interactive && !o.check {
+        let prompt = format!(
+          "{}: {} {} {} {:04o}{} ",
+          bold("fixext"),
+          bold("change the mode of"),
+          quote_filename(&path_str),
+          bold("to"),
+          new_mode,
+          bold("?")
+        );
+
+        if !confirm(&prompt) {
+          return decision;
+        }
       }
 
-      (desc, mime, result, dexts, mexts)
+      if !o.check && !o.dry {
+        if let Err(e) = modes::set_mode(&path, new_mode) {
+          message_path!(
+            path_str,
+            "{}",
+            bold_format!("ERROR: failed to change the mode ({}):", e)
+          );
+          return Decision::ChmodFailed;
+        }
+      }
+
+      modes_changed.set(modes_changed.get() + 1);
+
+      if o.shows(decision) {
+        match o.decisions {
+          true  => println!("exec-bits\t{}", line),
+          false => println!("{}", line),
+        }
+      }
+
+      decision
     };
 
-    let (exts, matched_desc) = match magic {
+    let (exts, matched_desc): (Vec<String>, String) = match magic {
       MagicMatch::Description(r, exts) => {
         verbose_path!(
           o,
@@ -515,21 +1591,73 @@ fn main() {
           "{}",
           bold_format!(
             "File description \"{}\" matches /{}/, extensions {:?}:",
-            desc,
+            m.desc,
             r,
             exts
           )
         );
-        (exts, &*desc)
+        (exts, m.desc.clone())
+      }
+      MagicMatch::Mime(mime, exts) => {
+        verbose_path!(
+          o,
+          path_str,
+          "{}",
+          bold_format!("File MIME \"{}\" matches, extensions {:?}:", mime, exts)
+        );
+        (exts, mime)
+      }
+      MagicMatch::Extension(exts) => {
+        verbose_path!(
+          o,
+          path_str,
+          "{}",
+          bold_format!("libmagic suggests extensions {:?}:", exts)
+        );
+        (exts, m.desc.clone())
+      }
+      MagicMatch::Compressed(zmime, exts) => {
+        verbose_path!(
+          o,
+          path_str,
+          "{}",
+          bold_format!(
+            "Compressed content \"{}\" in \"{}\" matches, extensions {:?}:",
+            zmime,
+            m.mime,
+            exts
+          )
+        );
+        (exts, format!("{} in {}", zmime, m.mime))
       }
-      MagicMatch::Mime(m, exts) => {
+      MagicMatch::Sniffed(mime, exts) => {
         verbose_path!(
           o,
           path_str,
           "{}",
-          bold_format!("File MIME \"{}\" matches, extensions {:?}:", m, exts)
+          bold_format!(
+            "Content of \"{}\" is sniffed as \"{}\" ({}), extensions {:?}:",
+            m.mime,
+            mime,
+            m.sniffed.detail,
+            exts
+          )
+        );
+        (exts, mime)
+      }
+      MagicMatch::Shebang(interp, exts) => {
+        verbose_path!(
+          o,
+          path_str,
+          "{}",
+          bold_format!(
+            "Script interpreter \"{}\" matches \"{}\", extensions {:?}:",
+            m.interp,
+            interp,
+            exts
+          )
         );
-        (exts, &*mime)
+        (exts, format!("{} script", interp))
       }
       MagicMatch::None => {
         verbose_path!(
@@ -538,17 +1666,36 @@ fn main() {
           "{}",
           bold_format!(
             "Unknown file type (description: \"{}\", MIME: {})",
-            desc,
-            mime
+            m.desc,
+            m.mime
           )
         );
-        (vec![], "(unknown)")
+        (vec![], String::from("(unknown)"))
       }
     };
 
-    if o.detect {
-      println!("{}: {}", path_str, matched_desc);
-      return Ok(());
+    // With -F and -I, nothing is renamed, so keep going after the first reason
+    // to skip the file, and report it alongside the match info instead.
+    let mut skipped: Option<Decision> = None;
+
+    macro_rules! skip {
+      ($decision:expr) => {
+        if o.detect || o.matchinfo {
+          skipped = skipped.or(Some($decision));
+        } else {
+          return decide!(path_str, fix_mode($decision));
+        }
+      };
+    }
+
+    if o.skipbintext && m.mime.starts_with("text/") && m.charset == "binary" {
+      verbose_path!(
+        o,
+        path_str,
+        "{}",
+        bold_format!("File is {} with binary encoding, skipping:", m.mime)
+      );
+      skip!(Decision::Ignored);
     }
 
     let (dirname, basename) = path_to_dir_base(&path);
@@ -556,32 +1703,83 @@ fn main() {
 
     let has_ext = basename.contains('.');
 
-    let (extdot_matched, ext) = match vec_si(&dotsplits[1..], o.extdot) {
-      Some(s) => (true, s.join(".")),
-      None    => (false, String::from(""))
+    // Without -L, split off the longest known multi-part extension, or
+    // whatever follows the last dot
+    let (extdot_matched, ext) = match (o.extdot, types.multi_ext(&basename)) {
+      (None, Some(multi)) => (true, multi.to_string()),
+      (extdot, _)         => match vec_si(&dotsplits[1..], extdot.unwrap_or(-1)) {
+        Some(s) => (true, s.join(".")),
+        None    => (false, String::from(""))
+      },
     };
 
-    if (!extdot_matched) && has_ext {
-      message_path!(
-        path_str,
-        "{}",
-        bold_format!(
-          "ERROR: the -L{} index is out of bounds for file, skipping:",
-          o.extdot
-        )
-      );
+    // For multi-part extensions split off automatically, any of the trailing
+    // parts is good enough (e.g. "tar.gz" for gzip if -z is not set)
+    let ext_parts: Vec<&str> = if o.extdot.is_none() {
+      ext.match_indices('.').map(|(i, _)| &ext[i + 1..]).collect()
+    } else {
+      vec![]
+    };
 
-      if !o.matchinfo {
-        return Err(String::from("extdot index out of bounds"));
+    // Audits look at every file, whatever its extension
+    if let Some(auditor) = &auditor {
+      let findings = auditor.findings(&path, &basename, &m);
+
+      if findings.is_empty() {
+        verbose_path!(o, path_str, "{}", bold("Nothing suspicious about file, skipping:"));
+        return decide!(path_str, Decision::AlreadyValid);
+      }
+
+      let mut line = format!("{}: {}", audit::escape_path(&path_str), findings.join("; "));
+
+      if let Some(dir) = &o.quarantine {
+        let dest = if o.dry {
+          Ok(audit::quarantine_preview(&basename, dir))
+        } else {
+          audit::quarantine(&path, &basename, dir)
+        };
+
+        match dest {
+          Ok(dest) => {
+            line = format!(
+              "{}{}; quarantined as {}",
+              if o.dry { "(DRY RUN) " } else { "" },
+              line,
+              audit::escape_path(&quote_filename(&dest.to_string_lossy()))
+            );
+          }
+          Err(e) => {
+            message_path!(
+              path_str,
+              "{}",
+              bold_format!("ERROR: failed to quarantine ({}):", e)
+            );
+            return decide!(path_str, Decision::RenameFailed, Some(line));
+          }
+        }
+      }
+
+      return decide!(path_str, Decision::Suspicious, Some(line));
+    }
+
+    if (!extdot_matched) && has_ext {
+      if !o.detect {
+        message_path!(
+          path_str,
+          "{}",
+          bold_format!(
+            "ERROR: the -L{} index is out of bounds for file, skipping:",
+            o.extdot.unwrap_or(-1)
+          )
+        );
       }
+
+      skip!(Decision::ExtdotOutOfBounds);
     }
 
     if exts == vec!["*"] {
       verbose_path!(o, path_str, "{}", bold("File ignored, skipping:"));
-
-      if !o.matchinfo {
-        return Ok(());
-      }
+      skip!(Decision::Ignored);
     }
 
     if exts.is_empty() {
@@ -591,23 +1789,83 @@ fn main() {
         "{}",
         bold("No extensions matched for file, skipping:")
       );
-
-      if !o.matchinfo {
-        return Err(String::from("No matched extensions"));
-      }
+      skip!(Decision::Unknown);
     }
 
-    if !ext.is_empty() && exts.contains(&ext) {
+    if (!ext.is_empty() && exts.contains(&ext))
+      || exts.iter().any(|e| e.contains('.') && ends_with_ext(&basename, e))
+      || ext_parts.iter().any(|p| exts.iter().any(|e| e == p))
+    {
       verbose_path!(
         o,
         path_str,
         "{}",
         bold("File has a valid matched extension, skipping:")
       );
+      skip!(Decision::AlreadyValid);
+    }
+
+    // Extensions compatible with libmagic's MIME type are fine too, as a
+    // sniffer may not tell e.g. a template from a document
+    let compatible = |e: &str| {
+      [&compat_mime, &m.mime]
+        .iter()
+        .copied()
+        .find(|mime| !e.is_empty() && types.is_compatible(mime, e))
+    };
+
+    if let Some(mime) = compatible(&ext).or_else(|| ext_parts.iter().find_map(|p| compatible(p))) {
+      verbose_path!(
+        o,
+        path_str,
+        "{}",
+        bold_format!("File extension is compatible with {}, skipping:", mime)
+      );
+      skip!(Decision::AlreadyValid);
+    }
 
-      if !o.matchinfo {
-        return Ok(());
+    // Generic types only get an extension if the file has none, unless asked
+    // to rename them like any other type
+    if generic
+      && (o.generic == GenericPolicy::Skip || (o.generic == GenericPolicy::Add && has_ext))
+    {
+      verbose_path!(
+        o,
+        path_str,
+        "{}",
+        bold_format!("File type \"{}\" is generic, skipping:", matched_desc)
+      );
+      skip!(Decision::Generic);
+    }
+
+    // Commands in bin/ directories are run by name, so they must stay as is
+    if o.skipscripts && !has_ext && !m.interp.is_empty() && is_executable(&path) {
+      verbose_path!(
+        o,
+        path_str,
+        "{}",
+        bold("File is an executable script without an extension, skipping:")
+      );
+      skip!(Decision::ExecScript);
+    }
+
+    if o.check {
+      if path_matches_any(&o.allowlist, &path) {
+        verbose_path!(o, path_str, "{}", bold("File mismatch is allowlisted, skipping:"));
+        return decide!(path_str, fix_mode(Decision::Allowlisted));
       }
+
+      return decide!(
+        path_str,
+        fix_mode(Decision::Mismatch),
+        Some(format!(
+          "{}: expected .{} ({}), found {}",
+          path_str,
+          exts[0],
+          compat_mime,
+          if has_ext { format!(".{}", ext) } else { String::from("no extension") }
+        ))
+      );
     }
 
     let new_ext = if !exts.is_empty() {
@@ -616,15 +1874,20 @@ fn main() {
       None
     };
 
+    // Replacing a wrong extension may reveal the right one, e.g. "tool.sh.txt"
     let new_basename: String =
       if let Some(e) = new_ext {
-        (
-          if o.append || !has_ext {
-            basename
-          } else {
-            String::from(&basename[0..basename.len() - ext.len() - 1])
-          }
-        ) + &*format!(".{}", e)
+        let stem = if o.append || !has_ext {
+          basename
+        } else {
+          String::from(&basename[0..basename.len() - ext.len() - 1])
+        };
+
+        if !o.append && ends_with_ext(&stem, &e) {
+          stem
+        } else {
+          stem + &*format!(".{}", e)
+        }
       }
       else {
         basename
@@ -636,19 +1899,6 @@ fn main() {
       new_fullname
     };
 
-    if o.matchinfo {
-      println!(
-        "{}\0{}\0{}\0{}\0{}\0{}\0",
-        path_str,
-        new_fullname.display(),
-        desc,
-        dexts.join(" "),
-        mime,
-        mexts.join(" ")
-      );
-      return Ok(());
-    }
-
     if new_fullname == *path {
       verbose_path!(
         o,
@@ -656,18 +1906,45 @@ fn main() {
         "{}",
         bold("Suggested file name equals to old, skipping:")
       );
-      return Err(String::from("attempted rename to same path"));
+      skip!(Decision::AlreadyValid);
+    }
+
+    let destination_exists = new_fullname.exists();
+
+    if destination_exists && !o.force && !o.interactive {
+      if !o.detect && !o.matchinfo {
+        message!(
+          "{} {} -> {}",
+          bold("Renaming will overwrite an existing file and -f is not set, skipping:"),
+          quote_filename(&path_str),
+          quote_filename(&new_fullname.as_os_str().to_string_lossy())
+        );
+      }
+
+      skip!(Decision::DestinationExists);
+    }
+
+    if o.detect {
+      let decision = skipped.unwrap_or(Decision::Renamed);
+      return decide!(path_str, decision, Some(format!("{}: {}", path_str, matched_desc)));
+    }
+
+    if o.matchinfo {
+      let decision = skipped.unwrap_or(Decision::Renamed);
+
+      return decide!(
+        path_str,
+        decision,
+        Some(m.record(&path_str, &new_fullname.to_string_lossy(), decision))
+      );
     }
 
-    let destination_exists      = new_fullname.exists();
     let old_fullname_str_quoted = quote_filename(&path_str);
     let new_fullname_str        = new_fullname.as_os_str().to_string_lossy().into_owned();
     let new_basename_str_quoted = quote_filename(&new_basename);
     let new_fullname_str_quoted = quote_filename(&new_fullname_str);
 
-    let do_rename: bool = if o.interactive {
-      let mut rl = Editor::<()>::new();
-
+    if o.interactive {
       let prompt = format!(
         "{}: {} {} {} {}{}{} ",
         bold("fixext"),
@@ -683,101 +1960,94 @@ fn main() {
         bold("?")
       );
 
-      let readline = rl.readline(&*prompt);
-
-      match readline {
-        Ok(line) => {
-          let yes: Regex = Regex::new(r"^\s*[yY]").unwrap();
-          yes.is_match(&*line)
-        }
-        Err(ReadlineError::Interrupted) => {
-          eprintln!("Received an interrupt");
-          process::exit(130);
-        }
-        _ => false,
+      if !confirm(&prompt) {
+        return decide!(path_str, Decision::Declined);
       }
-    } else if new_fullname.exists() && !o.force {
-      message!(
-        "{} {} -> {}",
-        bold("Renaming will overwrite an existing file and -f is not set, skipping:"),
-        old_fullname_str_quoted,
-        new_fullname_str_quoted
-      );
-      false
-    } else {
-      true
-    };
+    }
 
-    if do_rename {
-      println!(
-        "{}{} -> {}",
-        if o.dry { "(DRY RUN) " } else { "" },
-        old_fullname_str_quoted,
-        new_fullname_str_quoted
-      );
+    let rename_line = format!(
+      "{}{} -> {}",
+      if o.dry { "(DRY RUN) " } else { "" },
+      old_fullname_str_quoted,
+      new_fullname_str_quoted
+    );
 
-      if o.dry {
-        return Ok(());
-      }
+    if fix_mode(Decision::Renamed) == Decision::ChmodFailed {
+      return decide!(path_str, Decision::ChmodFailed);
+    }
 
-      if let Err(e) = fs::rename(path, new_fullname) {
-        message_path!(
-          path_str,
-          "{}",
-          bold_format!("ERROR: fs::rename failed ({}):", e)
-        );
-        return Err(format!("fs::rename failed: {}", e));
-      };
+    if o.dry {
+      return decide!(path_str, Decision::Renamed, Some(rename_line));
     }
 
-    return Ok(());
+    if let Err(e) = fs::rename(path, &new_fullname) {
+      message_path!(
+        path_str,
+        "{}",
+        bold_format!("ERROR: fs::rename failed ({}):", e)
+      );
+      return decide!(path_str, Decision::RenameFailed);
+    };
+
+    if o.watch.is_some() {
+      renamed_to.borrow_mut().push(new_fullname);
+    }
+
+    return decide!(path_str, Decision::Renamed, Some(rename_line));
   }; // file_visitor
 
-  let dir_visitor: &dyn Fn(PathBuf) -> Result<(), String> = &|path| {
+  let dir_visitor: &dyn Fn(PathBuf) -> Result<(), Decision> = &|path| {
     let path_str = path.as_os_str().to_string_lossy().into_owned();
 
-    if o.matchinfo {
-      let (desc, mime, dexts, mexts): (String, String, Vec<String>, Vec<String>) = {
-        let desc = c.desc.file(&path).unwrap_or_default();
-        let mime = c.mime.file(&path).unwrap_or_default();
+    if path_matches_any(&o.exclude, &path) {
+      verbose_path!(o, path_str, "{}", bold("Directory excluded, skipping:"));
+      return Err(decide!(path_str, Decision::Excluded));
+    }
 
-        let mut dexts: Vec<String> = vec![];
-        let mut mexts: Vec<String> = vec![];
+    let decision = if o.recursive { None } else { Some(Decision::Directory) };
 
-        for (r, exts) in &types.desc {
-          if r.is_match(&*desc) {
-            dexts = exts.clone();
-            break;
-          }
+    if o.matchinfo {
+      let m: MagicInfo = {
+        let d = magic_detect(&c, &path, None);
+
+        let mut m = MagicInfo {
+          desc:  d.desc,
+          mime:  d.mime,
+          xexts: magic_exts(&d.ext),
+          charset: d.charset,
+          ..Default::default()
+        };
+
+        if let Some((_, exts, _)) = types.desc.matches(&m.desc).next() {
+          m.dexts = tables::owned(exts);
         }
 
-        if let Some(exts) = types.mime.get(&mime) {
-          mexts = exts.clone();
+        if let Some((_, exts)) = types.mime_exts(&m.mime, &m.charset) {
+          m.mexts = tables::owned(exts);
         }
 
-        (desc, mime, dexts, mexts)
+        m
       };
 
-      println!(
-        "{}\0{}\0{}\0{}\0{}\0{}\0",
-        path_str,
-        path_str,
-        desc,
-        dexts.join(" "),
-        mime,
-        mexts.join(" ")
-      );
+      if o.shows(Decision::Directory) {
+        println!("{}", m.record(&path_str, &path_str, Decision::Directory));
+      }
     }
 
-    if !o.recursive {
+    if let Some(d) = decision {
       message!("{} {}", bold("File is a directory, skipping:"), path_str);
-      return Err(String::from("not recursing"));
+
+      if o.matchinfo {
+        return Err(d);
+      }
+
+      return Err(decide!(path_str, d));
     }
 
     return Ok(());
   };
 
-  let error_visitor: &dyn Fn(PathBuf, String) = &|path, estr| {
+  let error_visitor: &dyn Fn(PathBuf, String) -> Decision = &|path, estr| {
     let path_str = path.as_os_str().to_string_lossy().into_owned();
 
     message_path!(
@@ -785,14 +2055,344 @@ fn main() {
       "{}",
       bold_format!("Failed to read file metadata ({}):", estr)
     );
+
+    decide!(path_str, Decision::ReadFailed)
   };
 
-  files.for_each(|fp| {
-    visit_tree::<()>(
-      &PathBuf::from(&fp),
+  // Files under `roots` that will be visited, as far as their paths tell, to
+  // wait for those recently modified all at once
+  let scan_unstable = |roots: &[PathBuf]| {
+    if o.stablefor == 0 || o.detect || o.matchinfo {
+      return;
+    }
+
+    let mut paths: Vec<PathBuf> = vec![];
+
+    for root in roots {
+      visit_tree::<Option<PathBuf>>(
+        root,
+        &Some,
+        &|p| if o.recursive && !path_matches_any(&o.exclude, &p) { Ok(()) } else { Err(None) },
+        &|_, _| None,
+        &mut |p| paths.extend(p),
+      );
+    }
+
+    unstable.replace(Some(inprogress::Unstable::scan(paths, Duration::from_secs(o.stablefor))));
+  };
+
+  let mut summary = Summary { informational: o.detect || o.matchinfo, ..Default::default() };
+
+  let roots: Vec<PathBuf> = files.map(PathBuf::from).collect();
+  scan_unstable(&roots);
+
+  roots.iter().for_each(|fp| {
+    visit_tree::<Decision>(
+      fp,
       &file_visitor,
       &dir_visitor,
       &error_visitor,
+      &mut |d| summary.add(d),
     );
   });
+
+  let save_cache = || {
+    if let Some(cache) = &cache {
+      if let Err(e) = cache.borrow_mut().save() {
+        message!("{} {}", bold("Failed to save the cache:"), e);
+      }
+    }
+  };
+
+  if let Some(dir) = &o.watch {
+    let mut watcher = match watch::Watcher::new(dir, watch::DEFAULT_SETTLE) {
+      Ok(w)  => w,
+      Err(e) => {
+        message!("{} {}", bold("Cannot watch the directory:"), e);
+        process::exit(EXIT_ERROR);
+      }
+    };
+
+    save_cache();
+
+    loop {
+      let paths = match watcher.wait() {
+        Ok(p)  => p,
+        Err(e) => {
+          message!("{} {}", bold("Stopped watching the directory:"), e);
+          process::exit(EXIT_ERROR);
+        }
+      };
+
+      if watcher.overflowed() {
+        message!("{}", bold("Missed some events, looking at the whole directory again"));
+      }
+
+      writers.replace(None);
+
+      // Files may be gone again by the time they settle
+      let paths: Vec<PathBuf> = paths.into_iter().filter(|p| p.exists()).collect();
+      scan_unstable(&paths);
+
+      paths.iter().for_each(|p| {
+        visit_tree::<Decision>(p, &file_visitor, &dir_visitor, &error_visitor, &mut |_| ());
+      });
+
+      renamed_to.borrow_mut().drain(..).for_each(|p| watcher.ignore(&p));
+      save_cache();
+    }
+  }
+
+  summary.modes = modes_changed.get();
+  save_cache();
+
+  if !o.detect && !o.matchinfo {
+    let (fixes, fixes_label) = if o.check {
+      (summary.mismatch, "mismatched")
+    } else if o.audit {
+      (summary.suspicious, "suspicious")
+    } else if o.dry {
+      (summary.renamed, "to rename")
+    } else {
+      (summary.renamed, "renamed")
+    };
+
+    let modes = match (o.execbits, o.check, o.dry) {
+      (false, _, _)    => String::new(),
+      (true, true, _)  => format!(", {} exec bits mismatched", summary.modes),
+      (true, _, true)  => format!(", {} exec bits to fix", summary.modes),
+      (true, _, false) => format!(", {} exec bits fixed", summary.modes),
+    };
+
+    message!(
+      "{} {} {}, {} valid, {} unknown, {} ignored, {} collisions, {} errors{}",
+      bold("Summary:"),
+      fixes,
+      fixes_label,
+      summary.valid,
+      summary.unknown,
+      summary.ignored,
+      summary.collision,
+      summary.error,
+      modes
+    );
+  }
+
+  process::exit(summary.exit_code());
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // The built-in types, as without any options
+  fn types() -> Types {
+    Types {
+      desc: tables::Rules::new(&[], tables::DESC_TYPES).unwrap(),
+      mime: tables::Map::new(tables::MIME_TYPES, HashMap::new()),
+      compound: tables::COMPOUND_TYPES,
+      multi: tables::MULTI_EXTS,
+      generic_desc: RegexSet::new(tables::GENERIC_DESCS).unwrap(),
+      generic_mime: tables::GENERIC_MIMES.iter().map(|m| m.to_string()).collect(),
+      compat: tables::COMPAT_TYPES,
+      shebang: tables::SHEBANG_TYPES,
+    }
+  }
+
+  #[test]
+  fn exit_code() {
+    let mut summary = Summary::default();
+    assert_eq!(summary.exit_code(), EXIT_OK);
+
+    summary.add(Decision::AlreadyValid);
+    summary.add(Decision::Generic);
+    assert_eq!(summary.exit_code(), EXIT_OK);
+
+    summary.add(Decision::Renamed);
+    assert_eq!(summary.exit_code(), EXIT_FIXES);
+
+    summary.add(Decision::ExtdotOutOfBounds);
+    assert_eq!(summary.exit_code(), EXIT_ERROR);
+  }
+
+  #[test]
+  fn informational_exit_code() {
+    let mut summary = Summary { informational: true, ..Default::default() };
+
+    summary.add(Decision::Renamed);
+    summary.add(Decision::DestinationExists);
+    summary.add(Decision::ExtdotOutOfBounds);
+    summary.add(Decision::Mismatch);
+    assert_eq!(summary.exit_code(), EXIT_OK);
+
+    summary.add(Decision::ReadFailed);
+    assert_eq!(summary.exit_code(), EXIT_ERROR);
+  }
+
+  #[test]
+  fn interpreters() {
+    let t = types();
+
+    assert_eq!(t.shebang_exts("python3.11").map(|(i, _)| i).as_deref(), Some("python"));
+    assert_eq!(t.shebang_exts("bash").map(|(_, exts)| exts), Some(&["sh"][..]));
+    assert_eq!(t.shebang_exts("make"), None);
+  }
+
+  #[test]
+  fn overrides_are_told_apart() {
+    let overrides: &[(&str, tables::Exts)] = &[("^PNG image", &["png8"])];
+    let rules = tables::Rules::new(overrides, tables::DESC_TYPES).unwrap();
+    let matches: Vec<bool> = rules.matches("PNG image data").map(|(_, _, user)| user).collect();
+
+    assert_eq!(matches.first(), Some(&true));
+    assert!(matches[1..].iter().all(|user| !user));
+
+    let mut mime: HashMap<String, tables::Exts> = HashMap::new();
+    mime.insert(String::from("image/png"), &["png8"]);
+    let map = tables::Map::new(tables::MIME_TYPES, mime);
+
+    assert!(map.is_override("image/png"));
+    assert!(!map.is_override("image/gif"));
+  }
+
+  #[test]
+  fn office_variants_are_compatible() {
+    let t = types();
+    let ooxml = "application/vnd.openxmlformats-officedocument";
+
+    let docx = format!("{}.wordprocessingml.document", ooxml);
+    let xlsx = format!("{}.spreadsheetml.sheet", ooxml);
+    let pptx = format!("{}.presentationml.presentation", ooxml);
+
+    assert!(["dotx", "dotm"].iter().all(|e| t.is_compatible(&docx, e)));
+    assert!(["xltx", "xltm"].iter().all(|e| t.is_compatible(&xlsx, e)));
+    assert!(["potx", "potm", "ppsx", "ppsm"].iter().all(|e| t.is_compatible(&pptx, e)));
+    assert!(t.is_compatible("application/vnd.ms-word.document.macroenabled.12", "dotm"));
+    assert!(t.is_compatible("application/zip", "dotx"));
+
+    assert!(!t.is_compatible(&docx, "xlsx"));
+    assert!(!t.is_compatible(&docx, "docm"));
+
+    let xlsb = t.mime.get("application/vnd.ms-excel.sheet.binary.macroenabled.12");
+    assert_eq!(xlsb, Some(&["xlsb"][..]));
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn audit_exec_bits() {
+    let t = types();
+    let auditor = audit::Auditor::new(&t);
+    let path = env::temp_dir().join(format!("fixext-audit-{}", process::id()));
+
+    fs::write(&path, b"").unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let info = |desc: &str, mime: &str| MagicInfo {
+      desc: desc.to_string(),
+      mime: mime.to_string(),
+      ..Default::default()
+    };
+
+    let pdf = auditor.findings(&path, "report.pdf", &info("PDF document", "application/pdf"));
+    let text = auditor.findings(&path, "notes.txt", &info("ASCII text", "text/plain"));
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(pdf, ["exec bit set on PDF document (application/pdf)"]);
+    assert!(text.is_empty());
+  }
+
+  #[test]
+  fn magic_file_lists() {
+    let dir = env::temp_dir();
+    let mgc = dir.join(format!("fixext-magic-{}.mgc", process::id()));
+    let base = mgc.with_extension("");
+    let missing = dir.join("fixext-no-such-magic");
+
+    fs::write(&mgc, b"").unwrap();
+
+    let list = |paths: &[&Path]| env::join_paths(paths).unwrap().into_string().unwrap();
+    let found = validate_magic_files(list(&[&mgc, &base]));
+    let partly = validate_magic_files(list(&[&mgc, &missing]));
+    fs::remove_file(&mgc).unwrap();
+
+    assert!(found.is_ok());
+    assert!(partly.unwrap_err().contains("fixext-no-such-magic"));
+  }
+
+  #[test]
+  fn truncated_prefixes_are_not_buffered() {
+    let path = env::temp_dir().join(format!("fixext-prefix-{}", process::id()));
+
+    fs::write(&path, b"%PDF-1.4\n%%EOF\n").unwrap();
+
+    let short = prefix::read(&path, 4, false).unwrap();
+    let whole = prefix::read(&path, 1024, false).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(magic_buffer("PDF document", short.as_ref()), None);
+    assert_eq!(magic_buffer("PDF document", whole.as_ref()), Some(&b"%PDF-1.4\n%%EOF\n"[..]));
+    assert_eq!(magic_buffer("ELF 64-bit LSB executable", whole.as_ref()), None);
+  }
+
+  #[test]
+  fn sniffed_languages_are_generic() {
+    let t = types();
+    let m = MagicInfo { mime: String::from("text/plain"), ..Default::default() };
+    let rust = MagicMatch::Sniffed(String::from("text/x-rust"), vec![String::from("rs")]);
+    let markdown = MagicMatch::Sniffed(String::from("text/markdown"), vec![String::from("md")]);
+
+    assert!(t.is_generic(&m, &rust, Some("md")));
+    assert!(t.is_generic(&m, &rust, Some("txt")));
+    assert!(t.is_generic(&m, &rust, None));
+    assert!(t.is_generic(&m, &markdown, Some("log")));
+    assert!(!t.is_generic(&m, &markdown, Some("txt")));
+
+    // libmagic saw the language too
+    let c = MagicInfo { mime: String::from("text/x-c"), ..Default::default() };
+    let sniffed_c = MagicMatch::Sniffed(String::from("text/x-c"), vec![String::from("c")]);
+    assert!(!t.is_generic(&c, &sniffed_c, Some("txt")));
+  }
+
+  #[test]
+  fn compressed_contents() {
+    let t = types();
+    let inner = |zdesc: &str, zmime: &str| MagicInfo {
+      zdesc: zdesc.to_string(),
+      zmime: zmime.to_string(),
+      ..Default::default()
+    };
+
+    assert!(t.is_generic_inner(&inner("ASCII text", "text/plain")));
+    assert!(t.is_generic_inner(&inner("data", "application/octet-stream")));
+    assert!(!t.is_generic_inner(&inner("POSIX tar archive", "application/x-tar")));
+  }
+
+  #[test]
+  fn multi_part_extensions() {
+    let t = types();
+
+    assert_eq!(t.multi_ext("backup.tar.gz"), Some("tar.gz"));
+    assert_eq!(t.multi_ext("backup.gz"), None);
+    assert_eq!(t.multi_ext("tar.gz"), None);
+
+    assert!(ends_with_ext("notes.txt", "txt"));
+    assert!(!ends_with_ext("notestxt", "txt"));
+    assert!(!ends_with_ext(".txt", "txt"));
+    assert!(!ends_with_ext("txt", "txt"));
+  }
+
+  #[test]
+  fn overrides_are_parsed() {
+    let (key, exts) = parse_override("text/plain; charset=utf-8=txt, text", true).unwrap();
+    assert_eq!(key, "text/plain; charset=utf-8");
+    assert_eq!(exts, ["txt", "text"]);
+
+    let (key, exts) = parse_override("^PNG image=png apng", false).unwrap();
+    assert_eq!(key, "^PNG image");
+    assert_eq!(exts, ["png", "apng"]);
+
+    assert_eq!(parse_override("^PNG image=", false).unwrap().1, Vec::<String>::new());
+    assert!(parse_override("image/png", true).is_err());
+  }
 }
@@ -0,0 +1,279 @@
+// Files that are still being written: downloads under a temporary name,
+// files that keep changing, and files open for writing by another process
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+use std::time::SystemTime;
+
+#[cfg(target_os = "linux")]
+use std::collections::HashSet;
+#[cfg(target_os = "linux")]
+use std::os::unix::fs::MetadataExt;
+
+
+// Name suffixes of downloads in progress (compared in lowercase): browsers,
+// download managers and BitTorrent clients
+const PARTIAL_SUFFIXES: &[&str] = &[
+  ".part",
+  ".partial",
+  ".crdownload",
+  ".download",
+  ".opdownload",
+  ".!qb",
+  ".!ut",
+  ".!bt",
+  ".aria2",
+  ".filepart",
+];
+
+// Lock and owner files of office suites, next to the documents being edited
+const TEMP_PREFIXES: &[&str] = &[".~lock.", "~$"];
+
+// Some downloaders keep the data under the final name, and their progress in
+// a file with one of these suffixes next to it
+const COMPANION_SUFFIXES: &[&str] = &[".part", ".aria2"];
+
+// Why the name says the file is incomplete, if it does
+pub fn partial_name(path: &Path, basename: &str) -> Option<String> {
+  let lower = basename.to_lowercase();
+
+  if let Some(s) = PARTIAL_SUFFIXES.iter().find(|s| lower.ends_with(*s) && lower.len() > s.len()) {
+    return Some(format!("name ends with {}", s));
+  }
+
+  if let Some(p) = TEMP_PREFIXES.iter().find(|p| basename.starts_with(*p)) {
+    return Some(format!("name starts with {}", p));
+  }
+
+  COMPANION_SUFFIXES
+    .iter()
+    .map(|s| path.with_file_name(format!("{}{}", basename, s)))
+    .find(|c| c.exists())
+    .map(|c| format!("{} exists", c.to_string_lossy()))
+}
+
+fn stat(path: &Path) -> io::Result<(u64, SystemTime)> {
+  let md = fs::metadata(path)?;
+  Ok((md.len(), md.modified()?))
+}
+
+// How long ago the file was modified; a modification time in the future counts
+// as just now
+fn age(mtime: SystemTime) -> Duration {
+  SystemTime::now().duration_since(mtime).unwrap_or_default()
+}
+
+// Files whose size or modification time changes within `window` of their
+// last modification, found with a single wait for a whole batch of files
+pub struct Unstable {
+  window:  Duration,
+  changed: HashMap<PathBuf, bool>,
+}
+
+impl Unstable {
+  // Note the size and modification time of those of `paths` modified within
+  // `window`, wait until the most recently modified is `window` old, and look
+  // again; files left alone for longer are not waited for
+  pub fn scan(paths: Vec<PathBuf>, window: Duration) -> Unstable {
+    let mut recent: Vec<(PathBuf, io::Result<(u64, SystemTime)>)> = vec![];
+    let mut wait = Duration::default();
+
+    for path in paths {
+      let before = stat(&path);
+
+      if let Ok((_, mtime)) = before {
+        let age = age(mtime);
+
+        if age >= window {
+          continue;
+        }
+
+        wait = wait.max(window - age);
+      }
+
+      recent.push((path, before));
+    }
+
+    thread::sleep(wait);
+
+    let changed = recent
+      .into_iter()
+      .map(|(path, before)| {
+        let changed = match (before, stat(&path)) {
+          (Ok(before), Ok(after)) => before != after,
+          _                       => true,
+        };
+
+        (path, changed)
+      })
+      .collect();
+
+    Unstable { window, changed }
+  }
+
+  // Files the scan did not wait for are unstable if they have been modified
+  // within `window` since
+  pub fn contains(&self, path: &Path) -> bool {
+    match self.changed.get(path) {
+      Some(&changed) => changed,
+      None           => stat(path).map_or(true, |(_, mtime)| age(mtime) < self.window),
+    }
+  }
+}
+
+// Files open for writing by any process we may look at, by device and inode
+#[cfg(target_os = "linux")]
+pub struct Writers(HashSet<(u64, u64)>);
+
+#[cfg(target_os = "linux")]
+impl Writers {
+  // A snapshot of /proc/*/fd; processes of other users are invisible unless
+  // running as root
+  pub fn scan() -> Writers {
+    let mut open: HashSet<(u64, u64)> = HashSet::new();
+
+    let pids = match fs::read_dir("/proc") {
+      Ok(rd) => rd,
+      Err(_) => return Writers(open),
+    };
+
+    for pid in pids.flatten() {
+      let pid = pid.path();
+
+      let fds = match fs::read_dir(pid.join("fd")) {
+        Ok(rd) => rd,
+        Err(_) => continue,
+      };
+
+      for fd in fds.flatten() {
+        let writable = fs::read_to_string(pid.join("fdinfo").join(fd.file_name()))
+          .map_or(false, |info| Writers::writable(&info));
+
+        if writable {
+          if let Ok(md) = fs::metadata(fd.path()) {
+            open.insert((md.dev(), md.ino()));
+          }
+        }
+      }
+    }
+
+    Writers(open)
+  }
+
+  // fdinfo has the open(2) flags in octal, e.g. "flags:\t0100001"
+  fn writable(fdinfo: &str) -> bool {
+    fdinfo
+      .lines()
+      .find_map(|l| l.strip_prefix("flags:"))
+      .and_then(|f| i32::from_str_radix(f.trim(), 8).ok())
+      .map_or(false, |f| f & libc::O_ACCMODE != libc::O_RDONLY)
+  }
+
+  pub fn contains(&self, path: &Path) -> bool {
+    match fs::metadata(path) {
+      Ok(md) => self.0.contains(&(md.dev(), md.ino())),
+      Err(_) => false,
+    }
+  }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub struct Writers;
+
+#[cfg(not(target_os = "linux"))]
+impl Writers {
+  pub fn scan() -> Writers {
+    Writers
+  }
+
+  pub fn contains(&self, _path: &Path) -> bool {
+    false
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::env;
+  use std::process;
+
+  #[test]
+  fn partial_names() {
+    let dir = env::temp_dir();
+    let partial = |basename: &str| partial_name(&dir.join(basename), basename);
+
+    assert_eq!(partial("video.mp4.part").unwrap(), "name ends with .part");
+    assert_eq!(partial("setup.exe.crdownload").unwrap(), "name ends with .crdownload");
+    assert_eq!(partial("album.zip.!qB").unwrap(), "name ends with .!qb");
+    assert_eq!(partial("disk.iso.aria2").unwrap(), "name ends with .aria2");
+    assert_eq!(partial(".~lock.report.odt#").unwrap(), "name starts with .~lock.");
+    assert_eq!(partial("~$report.docx").unwrap(), "name starts with ~$");
+
+    assert_eq!(partial(".part"), None);
+    assert_eq!(partial("department.pdf"), None);
+    assert_eq!(partial("fixext-no-such-download.zip"), None);
+  }
+
+  #[test]
+  fn companion_files() {
+    let dir = env::temp_dir();
+    let basename = format!("fixext-download-{}.iso", process::id());
+    let companion = dir.join(format!("{}.aria2", basename));
+
+    fs::write(&companion, b"").unwrap();
+    let partial = partial_name(&dir.join(&basename), &basename);
+    fs::remove_file(&companion).unwrap();
+
+    assert_eq!(partial, Some(format!("{} exists", companion.to_string_lossy())));
+  }
+
+  #[test]
+  fn unstable_files() {
+    let dir = env::temp_dir();
+    let still = dir.join(format!("fixext-still-{}", process::id()));
+    let growing = dir.join(format!("fixext-growing-{}", process::id()));
+    let missing = dir.join(format!("fixext-missing-{}", process::id()));
+
+    fs::write(&still, b"done").unwrap();
+    fs::write(&growing, b"start").unwrap();
+
+    let writer = {
+      let growing = growing.clone();
+      thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        fs::write(&growing, b"started, and more").unwrap();
+      })
+    };
+
+    let window = Duration::from_millis(500);
+    let u = Unstable::scan(vec![still.clone(), growing.clone(), missing.clone()], window);
+    writer.join().unwrap();
+
+    // Not scanned, but just modified
+    let late = dir.join(format!("fixext-late-{}", process::id()));
+    fs::write(&late, b"").unwrap();
+
+    let unstable = [&still, &growing, &missing, &late].map(|p| u.contains(p));
+    fs::remove_file(&still).unwrap();
+    fs::remove_file(&growing).unwrap();
+    fs::remove_file(&late).unwrap();
+
+    assert_eq!(unstable, [false, true, true, true]);
+  }
+
+  #[cfg(target_os = "linux")]
+  #[test]
+  fn writable_fds() {
+    assert!(Writers::writable("pos:\t0\nflags:\t0100001\nmnt_id:\t29\n"));
+    assert!(Writers::writable("pos:\t0\nflags:\t02\n"));
+    assert!(!Writers::writable("pos:\t0\nflags:\t0100000\nmnt_id:\t29\n"));
+    assert!(!Writers::writable("pos:\t0\n"));
+  }
+}
@@ -0,0 +1,134 @@
+// A libmagic cookie over magic-sys's raw handle, for what the magic crate's
+// cookie has no method for (MAGIC_PARAM_BYTES_MAX)
+
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::path::Path;
+
+use magic::CookieFlags;
+
+use magic_sys::magic_t;
+
+
+// libmagic's limit on how much of a file or buffer it looks at
+const MAGIC_PARAM_BYTES_MAX: libc::c_int = 6;
+
+// Declared here rather than taken from magic-sys, whose declarations depend on
+// which libmagic version features it was built with
+extern "C" {
+  fn magic_open(flags: libc::c_int) -> magic_t;
+  fn magic_close(cookie: magic_t);
+  fn magic_error(cookie: magic_t) -> *const libc::c_char;
+  fn magic_file(cookie: magic_t, filename: *const libc::c_char) -> *const libc::c_char;
+  fn magic_buffer(
+    cookie: magic_t,
+    buffer: *const libc::c_void,
+    length: libc::size_t,
+  ) -> *const libc::c_char;
+  fn magic_load(cookie: magic_t, filename: *const libc::c_char) -> libc::c_int;
+  fn magic_load_buffers(
+    cookie: magic_t,
+    buffers: *mut *mut libc::c_void,
+    sizes: *mut libc::size_t,
+    nbuffers: libc::size_t,
+  ) -> libc::c_int;
+  fn magic_setparam(
+    cookie: magic_t,
+    param: libc::c_int,
+    value: *const libc::c_void,
+  ) -> libc::c_int;
+}
+
+
+pub struct Cookie(magic_t);
+
+impl Drop for Cookie {
+  fn drop(&mut self) {
+    unsafe { magic_close(self.0) }
+  }
+}
+
+impl Cookie {
+  pub fn open(flags: CookieFlags) -> Result<Cookie, String> {
+    let cookie = unsafe { magic_open(flags.bits()) };
+
+    if cookie.is_null() {
+      return Err(std::io::Error::last_os_error().to_string());
+    }
+
+    Ok(Cookie(cookie))
+  }
+
+  fn error(&self) -> String {
+    let e = unsafe { magic_error(self.0) };
+
+    if e.is_null() {
+      return "unknown error".to_string();
+    }
+
+    unsafe { CStr::from_ptr(e) }.to_string_lossy().into_owned()
+  }
+
+  fn string(&self, s: *const libc::c_char) -> Result<String, String> {
+    if s.is_null() {
+      return Err(self.error());
+    }
+
+    Ok(unsafe { CStr::from_ptr(s) }.to_string_lossy().into_owned())
+  }
+
+  fn status(&self, rc: libc::c_int) -> Result<(), String> {
+    if rc != 0 {
+      return Err(self.error());
+    }
+
+    Ok(())
+  }
+
+  pub fn file(&self, path: &Path) -> Result<String, String> {
+    let path = path_cstring(path)?;
+
+    self.string(unsafe { magic_file(self.0, path.as_ptr()) })
+  }
+
+  pub fn buffer(&self, buffer: &[u8]) -> Result<String, String> {
+    let len = buffer.len() as libc::size_t;
+
+    self.string(unsafe { magic_buffer(self.0, buffer.as_ptr() as *const libc::c_void, len) })
+  }
+
+  pub fn load(&self, path: &str) -> Result<(), String> {
+    let path = CString::new(path).map_err(|e| e.to_string())?;
+
+    self.status(unsafe { magic_load(self.0, path.as_ptr()) })
+  }
+
+  // libmagic keeps pointing into the buffer, hence 'static
+  pub fn load_buffer(&self, buffer: &'static [u8]) -> Result<(), String> {
+    let mut buffers = [buffer.as_ptr() as *mut libc::c_void];
+    let mut sizes = [buffer.len() as libc::size_t];
+
+    self.status(unsafe { magic_load_buffers(self.0, buffers.as_mut_ptr(), sizes.as_mut_ptr(), 1) })
+  }
+
+  pub fn set_bytes_max(&self, bytes: u64) -> Result<(), String> {
+    let value = bytes as libc::size_t;
+    let value = &value as *const libc::size_t as *const libc::c_void;
+
+    self.status(unsafe { magic_setparam(self.0, MAGIC_PARAM_BYTES_MAX, value) })
+  }
+}
+
+#[cfg(unix)]
+fn path_cstring(path: &Path) -> Result<CString, String> {
+  use std::os::unix::ffi::OsStrExt;
+
+  CString::new(path.as_os_str().as_bytes()).map_err(|e| e.to_string())
+}
+
+#[cfg(not(unix))]
+fn path_cstring(path: &Path) -> Result<CString, String> {
+  let path = path.to_str().ok_or_else(|| format!("not valid UTF-8: {}", path.display()))?;
+
+  CString::new(path).map_err(|e| e.to_string())
+}
@@ -0,0 +1,135 @@
+// Exec bits: programs and scripts should have them, and images, documents and
+// archives should not; other files are left alone
+
+#[cfg(unix)]
+use std::fs;
+use std::io;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use super::MagicInfo;
+
+
+// Native programs; scripts are told by their #! line instead, as e.g. Python
+// modules are not run by themselves
+const PROGRAM_MIMES: &[&str] = &[
+  "application/x-executable",
+  "application/x-pie-executable",
+  "application/x-mach-binary",
+  "application/x-elf",
+];
+
+// MIME type prefixes of files that are never run; vendor types are listed one
+// by one, as some are programs, e.g. Windows executables
+const DATA_MIMES: &[&str] = &[
+  "image/",
+  "audio/",
+  "video/",
+  "font/",
+  "application/pdf",
+  "application/postscript",
+  "application/rtf",
+  "text/rtf",
+  "application/msword",
+  "application/vnd.ms-excel",
+  "application/vnd.ms-powerpoint",
+  "application/vnd.ms-word.",
+  "application/vnd.ms-opentype",
+  "application/vnd.ms-cab-compressed",
+  "application/vnd.openxmlformats-officedocument.",
+  "application/vnd.oasis.opendocument.",
+  "application/vnd.rar",
+  "application/epub+zip",
+  "application/x-ole-storage",
+  "application/CDFV2",
+  "application/zip",
+  "application/gzip",
+  "application/x-gzip",
+  "application/x-bzip2",
+  "application/x-xz",
+  "application/zstd",
+  "application/x-lzip",
+  "application/x-7z-compressed",
+  "application/x-rar",
+  "application/x-tar",
+];
+
+const EXEC_BITS: u32 = 0o111;
+const READ_BITS: u32 = 0o444;
+const OWNER_EXEC: u32 = 0o100;
+
+// Whether a file of the MIME type `mime` should be executable, if it matters
+pub(crate) fn wants_exec(m: &MagicInfo, mime: &str) -> Option<bool> {
+  if !m.interp.is_empty() || PROGRAM_MIMES.contains(&mime) {
+    Some(true)
+  } else if DATA_MIMES.iter().any(|d| mime.starts_with(d)) {
+    Some(false)
+  } else {
+    None
+  }
+}
+
+// The mode with exec bits for whoever may read the file, or with none
+pub fn reconcile(mode: u32, exec: bool) -> u32 {
+  if exec {
+    mode | ((mode & READ_BITS) >> 2) | OWNER_EXEC
+  } else {
+    mode & !EXEC_BITS
+  }
+}
+
+pub fn has_exec(mode: u32) -> bool {
+  mode & EXEC_BITS != 0
+}
+
+// The permission bits of the file at `path`, if there are exec bits at all
+#[cfg(unix)]
+pub fn mode(path: &Path) -> io::Result<Option<u32>> {
+  Ok(Some(fs::metadata(path)?.permissions().mode() & 0o7777))
+}
+
+#[cfg(not(unix))]
+pub fn mode(_path: &Path) -> io::Result<Option<u32>> {
+  Ok(None)
+}
+
+#[cfg(unix)]
+pub fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+  fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+pub fn set_mode(_path: &Path, _mode: u32) -> io::Result<()> {
+  Err(io::Error::new(io::ErrorKind::Other, "there are no exec bits"))
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn exec_by_type() {
+    let m = MagicInfo::default();
+    let ooxml = "application/vnd.openxmlformats-officedocument.wordprocessingml.document";
+
+    assert_eq!(wants_exec(&m, "application/x-pie-executable"), Some(true));
+    assert_eq!(wants_exec(&m, "image/png"), Some(false));
+    assert_eq!(wants_exec(&m, ooxml), Some(false));
+    assert_eq!(wants_exec(&m, "application/vnd.microsoft.portable-executable"), None);
+    assert_eq!(wants_exec(&m, "text/plain"), None);
+
+    let script = MagicInfo { interp: String::from("sh"), ..Default::default() };
+    assert_eq!(wants_exec(&script, "text/plain"), Some(true));
+  }
+
+  #[test]
+  fn modes() {
+    assert_eq!(reconcile(0o644, true), 0o755);
+    assert_eq!(reconcile(0o600, true), 0o700);
+    assert_eq!(reconcile(0o755, false), 0o644);
+    assert!(has_exec(0o744));
+    assert!(!has_exec(0o644));
+  }
+}
@@ -0,0 +1,90 @@
+// Reading a bounded prefix of each file once, to run all libmagic cookies on
+// the same buffer instead of opening the file for each of them
+
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::ops::Deref;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+
+pub struct Prefix {
+  bytes: Bytes,
+  // Whether the prefix is the whole file
+  pub whole: bool,
+}
+
+enum Bytes {
+  Read(Vec<u8>),
+  #[cfg(unix)]
+  Mapped(*const u8, usize),
+}
+
+impl Deref for Prefix {
+  type Target = [u8];
+
+  fn deref(&self) -> &[u8] {
+    match &self.bytes {
+      Bytes::Read(b)        => b,
+      #[cfg(unix)]
+      Bytes::Mapped(p, len) => unsafe { std::slice::from_raw_parts(*p, *len) },
+    }
+  }
+}
+
+#[cfg(unix)]
+impl Drop for Bytes {
+  fn drop(&mut self) {
+    if let Bytes::Mapped(p, len) = self {
+      unsafe {
+        libc::munmap(*p as *mut libc::c_void, *len);
+      }
+    }
+  }
+}
+
+// The first `len` bytes of the file at `path`, mapped into memory if asked
+// to (and possible), or None if it is not a non-empty regular file: libmagic
+// tells those apart by their metadata, not their content
+pub fn read(path: &Path, len: u64, mmap: bool) -> io::Result<Option<Prefix>> {
+  let md = fs::symlink_metadata(path)?;
+
+  if !md.is_file() || md.len() == 0 {
+    return Ok(None);
+  }
+
+  let mut f = fs::File::open(path)?;
+  let whole = len >= md.len();
+  let len = len.min(md.len());
+
+  if mmap {
+    if let Some(bytes) = map(&f, len as usize) {
+      return Ok(Some(Prefix { bytes, whole }));
+    }
+  }
+
+  let mut buf: Vec<u8> = Vec::with_capacity(len as usize);
+  (&mut f).take(len).read_to_end(&mut buf)?;
+
+  Ok(Some(Prefix { bytes: Bytes::Read(buf), whole }))
+}
+
+#[cfg(unix)]
+fn map(f: &fs::File, len: usize) -> Option<Bytes> {
+  let p = unsafe {
+    libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ, libc::MAP_PRIVATE, f.as_raw_fd(), 0)
+  };
+
+  if p == libc::MAP_FAILED {
+    None
+  } else {
+    Some(Bytes::Mapped(p as *const u8, len))
+  }
+}
+
+#[cfg(not(unix))]
+fn map(_f: &fs::File, _len: usize) -> Option<Bytes> {
+  None
+}
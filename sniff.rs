@@ -0,0 +1,182 @@
+// Format-specific sniffers that look inside files to refine a libmagic match,
+// e.g. to tell an OOXML document from a plain ZIP archive
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::convert::TryInto;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::path::Path;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+mod ebml;
+mod font;
+mod isobmff;
+mod lang;
+mod ogg;
+mod shebang;
+mod text;
+mod tiff;
+mod zip;
+
+
+// A refined MIME type, and what gave it away (e.g. a ZIP member name)
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Sniffed {
+  pub mime:   String,
+  pub detail: String,
+}
+
+// What sniffers look at: the prefix of a regular file that was read for
+// libmagic, and the rest of it, only opened if a sniffer needs more than that
+pub struct Content<'a> {
+  path:   &'a Path,
+  prefix: &'a [u8],
+  len:    u64,
+  file:   RefCell<Option<fs::File>>,
+}
+
+impl<'a> Content<'a> {
+  pub fn new(path: &'a Path, prefix: &'a [u8]) -> io::Result<Content<'a>> {
+    let len = fs::metadata(path)?.len();
+    Ok(Content { path, prefix, len, file: RefCell::new(None) })
+  }
+
+  // Content that is all in memory
+  #[cfg(test)]
+  fn from_bytes(b: &'a [u8]) -> Content<'a> {
+    Content { path: Path::new(""), prefix: b, len: b.len() as u64, file: RefCell::new(None) }
+  }
+
+  // Up to `len` bytes at the start of the file, as far as the prefix goes
+  fn head(&self, len: u64) -> &'a [u8] {
+    &self.prefix[..self.prefix.len().min(len as usize)]
+  }
+
+  // Whether the file goes on after `head`
+  fn truncated(&self, head: &[u8]) -> bool {
+    self.len > head.len() as u64
+  }
+
+  // Up to `len` bytes at `offset`, read from the file unless the prefix has
+  // all of them
+  fn read_at(&self, offset: u64, len: u64) -> io::Result<Cow<'a, [u8]>> {
+    let end = offset.saturating_add(len).min(self.len);
+
+    if end <= self.prefix.len() as u64 {
+      return Ok(Cow::Borrowed(&self.prefix[offset.min(end) as usize..end as usize]));
+    }
+
+    let mut file = self.file.borrow_mut();
+
+    if file.is_none() {
+      *file = Some(fs::File::open(self.path)?);
+    }
+
+    let f = file.as_mut().unwrap();
+    let mut buf: Vec<u8> = vec![];
+
+    f.seek(SeekFrom::Start(offset))?;
+    f.take(len).read_to_end(&mut buf)?;
+
+    Ok(Cow::Owned(buf))
+  }
+}
+
+type Sniffer = fn(&Content, &str) -> io::Result<Option<Sniffed>>;
+
+// Each sniffer is only tried on files with one of its libmagic MIME types
+const SNIFFERS: &[(&[&str], Sniffer)] = &[
+  (zip::MIMES,     zip::sniff),
+  (isobmff::MIMES, isobmff::sniff),
+  (ebml::MIMES,    ebml::sniff),
+  (ogg::MIMES,     ogg::sniff),
+  (tiff::MIMES,    tiff::sniff),
+  (font::MIMES,    font::sniff),
+  (text::MIMES,    text::sniff),
+];
+
+// Refine the libmagic MIME type of `content`, if there is a sniffer for it;
+// I/O errors and malformed files just mean there is no refinement. Text that
+// is none of the structured formats may still look like Markdown or a table,
+// and failing that, be source code, which is only trusted with a confidence
+// of at least `lang_threshold`; Markdown comes first, as READMEs are full of
+// code blocks
+pub fn refine(content: &Content, mime: &str, lang_threshold: u32) -> Option<Sniffed> {
+  SNIFFERS
+    .iter()
+    .filter(|(mimes, _)| mimes.contains(&mime))
+    .find_map(|(_, sniff)| sniff(content, mime).ok().flatten())
+    .or_else(|| text::guess(content, mime))
+    .or_else(|| match lang::MIMES.contains(&mime) {
+      true  => lang::sniff(content, lang_threshold),
+      false => None,
+    })
+}
+
+// Whether a MIME type only says what text looks like, e.g. "text/csv"
+pub fn is_guess(mime: &str) -> bool {
+  text::GUESSES.contains(&mime)
+}
+
+// Whether a MIME type is one of the source languages told by their syntax
+pub fn is_language(mime: &str) -> bool {
+  lang::is_language(mime)
+}
+
+// The interpreter named on the #! line of `content`, if any
+pub fn interpreter(content: &Content) -> Option<String> {
+  shebang::interpreter(content)
+}
+
+macro_rules! int_at {
+  ($name:ident, $t:ty, $from:ident) => {
+    #[allow(dead_code)]
+    fn $name(b: &[u8], i: usize) -> Option<$t> {
+      let bytes = b.get(i..i.checked_add(std::mem::size_of::<$t>())?)?;
+      Some(<$t>::$from(bytes.try_into().unwrap()))
+    }
+  };
+}
+
+int_at!(le_u16, u16, from_le_bytes);
+int_at!(le_u32, u32, from_le_bytes);
+int_at!(le_u64, u64, from_le_bytes);
+int_at!(be_u16, u16, from_be_bytes);
+int_at!(be_u32, u32, from_be_bytes);
+int_at!(be_u64, u64, from_be_bytes);
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn refined(t: &str, mime: &str) -> Option<String> {
+    refine(&Content::from_bytes(t.as_bytes()), mime, 60).map(|s| s.mime)
+  }
+
+  #[test]
+  fn markdown_before_languages() {
+    let readme = "# mycrate\n\n\
+                  A parser for things.\n\n\
+                  ## Usage\n\n\
+                  ```rust\n\
+                  use mycrate::Parser;\n\n\
+                  fn main() {\n\
+                  \x20   let p = Parser::new();\n\
+                  \x20   println!(\"{:?}\", p.parse(\"x\"));\n\
+                  }\n\
+                  ```\n";
+    assert_eq!(refined(readme, "text/plain").as_deref(), Some("text/markdown"));
+    assert_eq!(refined(readme, "text/x-c").as_deref(), Some("text/markdown"));
+
+    let rust = "use mycrate::Parser;\n\n\
+                fn main() {\n  let p = Parser::new();\n  println!(\"{:?}\", p);\n}\n";
+    assert_eq!(refined(rust, "text/plain").as_deref(), Some("text/x-rust"));
+  }
+}
@@ -0,0 +1,230 @@
+// Matroska and WebM: the EBML header has the DocType, and Matroska files are
+// audio-only (mka) if none of their tracks is video
+
+use std::io;
+
+use super::Content;
+use super::Sniffed;
+
+
+pub const MIMES: &[&str] = &[
+  "video/x-matroska",
+  "audio/x-matroska",
+  "video/webm",
+  "audio/webm",
+  "application/octet-stream",
+];
+
+const EBML_ID:       u32 = 0x1a45_dfa3;
+const DOCTYPE_ID:    u32 = 0x4282;
+const SEGMENT_ID:    u32 = 0x1853_8067;
+const TRACKS_ID:     u32 = 0x1654_ae6b;
+const TRACKENTRY_ID: u32 = 0xae;
+const TRACKTYPE_ID:  u32 = 0x83;
+const CLUSTER_ID:    u32 = 0x1f43_b675;
+
+const TRACK_VIDEO:    u64 = 1;
+const TRACK_AUDIO:    u64 = 2;
+const TRACK_SUBTITLE: u64 = 0x11;
+
+// Tracks come before the first cluster, so they should be in here
+const MAX_PREFIX_LEN: u64 = 1 << 20;
+
+// An EBML variable-length integer at `i`, its length, and whether all of its
+// value bits are set (meaning "unknown" for sizes)
+fn vint(b: &[u8], i: usize, keep_marker: bool) -> Option<(u64, usize, bool)> {
+  let first = *b.get(i)?;
+  let len = first.leading_zeros() as usize + 1;
+
+  if len > 8 {
+    return None;
+  }
+
+  let bytes = b.get(i..i + len)?;
+  let mask: u64 = (1u64 << (7 * len)) - 1;
+  let raw = bytes.iter().fold(0u64, |acc, &byte| (acc << 8) | byte as u64);
+
+  let value = if keep_marker { raw } else { raw & mask };
+
+  Some((value, len, raw & mask == mask))
+}
+
+// ID, data offset and data end of each element in [start, end); an element of
+// unknown size extends to `end`
+fn elements(b: &[u8], start: usize, end: usize) -> Vec<(u32, usize, usize)> {
+  let mut result: Vec<(u32, usize, usize)> = vec![];
+  let mut i = start;
+
+  while i < end {
+    let (id, id_len) = match vint(b, i, true) {
+      Some((id, len, _)) if len <= 4 => (id as u32, len),
+      _                              => break,
+    };
+
+    let (size, size_len, unknown) = match vint(b, i + id_len, false) {
+      Some(s) => s,
+      None    => break,
+    };
+
+    let data = i + id_len + size_len;
+
+    if data > end {
+      break;
+    }
+
+    let data_end = if unknown { end } else { data.saturating_add(size as usize).min(end) };
+
+    result.push((id, data, data_end));
+
+    if data_end >= end {
+      break;
+    }
+
+    i = data_end;
+  }
+
+  result
+}
+
+fn uint(b: &[u8]) -> u64 {
+  b.iter().take(8).fold(0u64, |acc, &byte| (acc << 8) | byte as u64)
+}
+
+pub fn sniff(c: &Content, _mime: &str) -> io::Result<Option<Sniffed>> {
+  let b = c.head(MAX_PREFIX_LEN);
+
+  let top = elements(b, 0, b.len());
+
+  let doctype = match top.first() {
+    Some(&(EBML_ID, start, end)) => elements(b, start, end)
+      .into_iter()
+      .find(|(id, _, _)| *id == DOCTYPE_ID)
+      .map(|(_, s, e)| String::from_utf8_lossy(&b[s..e]).trim_end_matches('\0').to_string()),
+    _ => return Ok(None),
+  };
+
+  let doctype = match doctype {
+    Some(d) => d,
+    None    => return Ok(None),
+  };
+
+  let mut tracks: Vec<u64> = vec![];
+
+  if let Some(&(_, start, end)) = top.iter().find(|(id, _, _)| *id == SEGMENT_ID) {
+    for (id, start, end) in elements(b, start, end) {
+      match id {
+        TRACKS_ID => {
+          for (id, start, end) in elements(b, start, end) {
+            if id != TRACKENTRY_ID {
+              continue;
+            }
+
+            if let Some((_, s, e)) =
+              elements(b, start, end).into_iter().find(|(id, _, _)| *id == TRACKTYPE_ID)
+            {
+              tracks.push(uint(&b[s..e]));
+            }
+          }
+        }
+        CLUSTER_ID => break,
+        _          => (),
+      }
+    }
+  }
+
+  let track_names: Vec<&str> = tracks
+    .iter()
+    .map(|t| match *t {
+      TRACK_VIDEO    => "video",
+      TRACK_AUDIO    => "audio",
+      TRACK_SUBTITLE => "subtitle",
+      _              => "other",
+    })
+    .collect();
+
+  let mut detail = format!("DocType {}", doctype);
+
+  if !track_names.is_empty() {
+    detail += &format!(", tracks {}", track_names.join(" "));
+  }
+
+  let audio_only = tracks.contains(&TRACK_AUDIO) && !tracks.contains(&TRACK_VIDEO);
+
+  let mime = match (doctype.as_str(), audio_only) {
+    ("webm", _)         => "video/webm",
+    ("matroska", true)  => "audio/x-matroska",
+    ("matroska", false) => "video/x-matroska",
+    _                   => return Ok(None),
+  };
+
+  Ok(Some(Sniffed { mime: mime.to_string(), detail }))
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // An element with the shortest encoding of its ID and size
+  fn el(id: u32, data: &[u8]) -> Vec<u8> {
+    let mut b: Vec<u8> = id.to_be_bytes().iter().copied().skip_while(|byte| *byte == 0).collect();
+
+    match data.len() {
+      n if n < 0x7f => b.push(0x80 | n as u8),
+      n             => b.extend(&(0x4000 | n as u16).to_be_bytes()),
+    }
+
+    b.extend(data);
+    b
+  }
+
+  fn file(doctype: &str, tracks: &[u8]) -> Vec<u8> {
+    let entries: Vec<u8> =
+      tracks.iter().flat_map(|t| el(TRACKENTRY_ID, &el(TRACKTYPE_ID, &[*t]))).collect();
+
+    let mut b = el(EBML_ID, &el(DOCTYPE_ID, doctype.as_bytes()));
+    b.extend(el(SEGMENT_ID, &el(TRACKS_ID, &entries)));
+    b
+  }
+
+  fn sniffed(b: &[u8]) -> Option<String> {
+    sniff(&Content::from_bytes(b), "video/x-matroska").unwrap().map(|s| s.mime)
+  }
+
+  #[test]
+  fn vints() {
+    assert_eq!(vint(&[0x81], 0, false), Some((1, 1, false)));
+    assert_eq!(vint(&[0x40, 0x02], 0, false), Some((2, 2, false)));
+    assert_eq!(vint(&[0x1a, 0x45, 0xdf, 0xa3], 0, true), Some((0x1a45_dfa3, 4, false)));
+
+    // All value bits set, for an unknown size
+    let unknown = [0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+    assert_eq!(vint(&unknown, 0, false), Some(((1 << 56) - 1, 8, true)));
+
+    // No marker bit, or cut short
+    assert_eq!(vint(&[0x00], 0, false), None);
+    assert_eq!(vint(&[0x40], 0, false), None);
+  }
+
+  #[test]
+  fn doctypes_and_tracks() {
+    assert_eq!(sniffed(&file("webm", &[1, 2])).as_deref(), Some("video/webm"));
+    assert_eq!(sniffed(&file("matroska", &[1, 2, 0x11])).as_deref(), Some("video/x-matroska"));
+    assert_eq!(sniffed(&file("matroska", &[2, 0x11])).as_deref(), Some("audio/x-matroska"));
+    assert_eq!(sniffed(&file("matroska", &[])).as_deref(), Some("video/x-matroska"));
+
+    // NUL padding, and a segment of unknown size
+    let mut b = el(EBML_ID, &el(DOCTYPE_ID, b"matroska\0\0"));
+    b.extend(&[0x18, 0x53, 0x80, 0x67, 0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+    b.extend(el(TRACKS_ID, &el(TRACKENTRY_ID, &el(TRACKTYPE_ID, &[2]))));
+    assert_eq!(sniffed(&b).as_deref(), Some("audio/x-matroska"));
+  }
+
+  #[test]
+  fn not_matroska() {
+    assert_eq!(sniffed(&file("other", &[1])), None);
+    assert_eq!(sniffed(&el(EBML_ID, &[])), None);
+    assert_eq!(sniffed(&el(SEGMENT_ID, &[])), None);
+    assert_eq!(sniffed(b""), None);
+  }
+}
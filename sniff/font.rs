@@ -0,0 +1,187 @@
+// Fonts: sfnt files (TrueType and OpenType) are told apart by their outlines,
+// "glyf" or "CFF " tables, and WOFF, WOFF2, TTC and Type 1 files by their
+// signatures, as libmagic MIME types for these vary across versions
+
+use std::io;
+
+use super::Content;
+use super::Sniffed;
+use super::be_u16;
+use super::be_u32;
+
+
+pub const MIMES: &[&str] = &[
+  "font/sfnt",
+  "font/ttf",
+  "font/otf",
+  "font/collection",
+  "font/woff",
+  "font/woff2",
+  "application/font-sfnt",
+  "application/font-woff",
+  "application/vnd.ms-opentype",
+  "application/x-font-ttf",
+  "application/x-font-otf",
+  "application/x-font-type1",
+  "font/x-postscript-pfb",
+  "application/octet-stream",
+  // Type 1 fonts in PFA format are plain text to libmagic
+  "text/plain",
+];
+
+const PREFIX_LEN:   u64 = 16;
+const HEADER_LEN:   u64 = 12;
+const RECORD_LEN:   u64 = 16;
+const MAX_TABLES:   u64 = 1024;
+const PFB_LEN:      u64 = 6;
+const TYPE1_MAGICS: &[&[u8]] = &[b"%!PS-AdobeFont", b"%!FontType1"];
+
+// sfnt version tags
+const TRUETYPE:     u32 = 0x0001_0000;
+const TRUETYPE_MAC: &[u8] = b"true";
+const CFF:          &[u8] = b"OTTO";
+const COLLECTION:   &[u8] = b"ttcf";
+
+fn flavor(tag: &[u8]) -> String {
+  match tag {
+    CFF                                   => "CFF".to_string(),
+    TRUETYPE_MAC                          => "TrueType".to_string(),
+    COLLECTION                            => "collection".to_string(),
+    _ if be_u32(tag, 0) == Some(TRUETYPE) => "TrueType".to_string(),
+    _                                     => format!("{:02x?}", tag),
+  }
+}
+
+pub fn sniff(c: &Content, _mime: &str) -> io::Result<Option<Sniffed>> {
+  let header = c.read_at(0, PREFIX_LEN)?;
+
+  macro_rules! sniffed {
+    ($mime:expr, $detail:expr) => {
+      return Ok(Some(Sniffed { mime: $mime.to_string(), detail: $detail.to_string() }))
+    };
+  }
+
+  let tag = match header.get(0..4) {
+    Some(t) => t,
+    None    => return Ok(None),
+  };
+
+  match tag {
+    b"wOFF" | b"wOF2" => {
+      let (mime, name) = if tag == b"wOFF" { ("font/woff", "WOFF") } else { ("font/woff2", "WOFF2") };
+
+      match header.get(4..8) {
+        Some(f) => sniffed!(mime, format!("{}, flavor {}", name, flavor(f))),
+        None    => return Ok(None),
+      }
+    }
+    COLLECTION => {
+      let fonts = be_u32(&header, 8).unwrap_or_default();
+      sniffed!("font/collection", format!("TTC, {} fonts", fonts));
+    }
+    [0x80, 0x01, ..] => {
+      // A PFB segment header (marker, type, length) before the PFA text
+      let text = c.read_at(PFB_LEN, PREFIX_LEN)?;
+
+      if TYPE1_MAGICS.iter().any(|m| text.starts_with(m)) {
+        sniffed!("font/x-postscript-pfb", "Type 1, PFB segments");
+      }
+
+      return Ok(None);
+    }
+    _ if TYPE1_MAGICS.iter().any(|m| header.starts_with(m)) => {
+      sniffed!("font/x-postscript-pfa", "Type 1, PFA text");
+    }
+    _ => (),
+  }
+
+  if tag != CFF && tag != TRUETYPE_MAC && be_u32(tag, 0) != Some(TRUETYPE) {
+    return Ok(None);
+  }
+
+  let count = (be_u16(&header, 4).unwrap_or_default() as u64).min(MAX_TABLES);
+  let records = c.read_at(HEADER_LEN, count * RECORD_LEN)?;
+  let tables: Vec<&[u8]> = records.chunks_exact(RECORD_LEN as usize).map(|r| &r[0..4]).collect();
+
+  let has = |t: &[u8]| tables.contains(&t);
+
+  if has(b"CFF ") || has(b"CFF2") {
+    sniffed!("font/otf", format!("sfnt {}, CFF outlines", flavor(tag)));
+  }
+
+  if has(b"glyf") {
+    sniffed!("font/ttf", format!("sfnt {}, TrueType outlines", flavor(tag)));
+  }
+
+  // E.g. bitmap-only (color emoji) fonts
+  match tag {
+    CFF => sniffed!("font/otf", format!("sfnt {}, no outlines", flavor(tag))),
+    _   => sniffed!("font/ttf", format!("sfnt {}, no outlines", flavor(tag))),
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // An sfnt header and table records, without the tables themselves
+  fn sfnt(version: &[u8], tables: &[&[u8]]) -> Vec<u8> {
+    let mut b: Vec<u8> = version.to_vec();
+    b.extend(&(tables.len() as u16).to_be_bytes());
+    b.extend(&[0; 6]);
+    tables.iter().for_each(|t| b.extend(t.iter().chain(&[0; 12])));
+    b
+  }
+
+  fn sniffed(b: &[u8]) -> Option<Sniffed> {
+    sniff(&Content::from_bytes(b), "font/sfnt").unwrap()
+  }
+
+  fn mime(b: &[u8]) -> Option<String> {
+    sniffed(b).map(|s| s.mime)
+  }
+
+  #[test]
+  fn sfnt_outlines() {
+    let ttf = sniffed(&sfnt(&[0, 1, 0, 0], &[b"cmap", b"glyf", b"loca"])).unwrap();
+    assert_eq!(ttf.mime, "font/ttf");
+    assert_eq!(ttf.detail, "sfnt TrueType, TrueType outlines");
+
+    assert_eq!(mime(&sfnt(b"OTTO", &[b"CFF ", b"cmap"])).as_deref(), Some("font/otf"));
+    assert_eq!(mime(&sfnt(&[0, 1, 0, 0], &[b"CFF2"])).as_deref(), Some("font/otf"));
+    assert_eq!(mime(&sfnt(b"true", &[b"glyf"])).as_deref(), Some("font/ttf"));
+
+    // Bitmap-only fonts go by their version tag
+    assert_eq!(mime(&sfnt(b"OTTO", &[b"CBDT", b"CBLC"])).as_deref(), Some("font/otf"));
+    assert_eq!(mime(&sfnt(&[0, 1, 0, 0], &[b"sbix"])).as_deref(), Some("font/ttf"));
+  }
+
+  #[test]
+  fn signatures() {
+    let woff = sniffed(b"wOFFOTTO\0\0\0\0\0\0\0\0").unwrap();
+    assert_eq!(woff.mime, "font/woff");
+    assert_eq!(woff.detail, "WOFF, flavor CFF");
+
+    let woff2 = sniffed(b"wOF2\0\x01\0\0\0\0\0\0\0\0\0\0").unwrap();
+    assert_eq!(woff2.mime, "font/woff2");
+    assert_eq!(woff2.detail, "WOFF2, flavor TrueType");
+
+    let ttc = sniffed(b"ttcf\0\x01\0\0\0\0\0\x03").unwrap();
+    assert_eq!(ttc.mime, "font/collection");
+    assert_eq!(ttc.detail, "TTC, 3 fonts");
+
+    assert_eq!(mime(b"%!PS-AdobeFont-1.0: Foo").as_deref(), Some("font/x-postscript-pfa"));
+
+    let pfb = b"\x80\x01\x10\0\0\0%!FontType1-1.0";
+    assert_eq!(mime(pfb).as_deref(), Some("font/x-postscript-pfb"));
+  }
+
+  #[test]
+  fn not_fonts() {
+    assert_eq!(mime(b"\x80\x01\x10\0\0\0garbage"), None);
+    assert_eq!(mime(b"%!PS-Adobe-3.0"), None);
+    assert_eq!(mime(b"Hello, world"), None);
+    assert_eq!(mime(b"OT"), None);
+  }
+}
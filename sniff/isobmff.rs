@@ -0,0 +1,275 @@
+// ISO base media files (MP4, QuickTime, HEIF...): the "ftyp" brands tell most
+// of them apart, and files with only generic brands are audio-only MP4 if none
+// of their tracks is video
+
+use std::io;
+
+use super::Content;
+use super::Sniffed;
+use super::be_u32;
+use super::be_u64;
+
+
+pub const MIMES: &[&str] = &[
+  "video/mp4",
+  "video/quicktime",
+  "video/x-m4v",
+  "video/3gpp",
+  "video/3gpp2",
+  "audio/mp4",
+  "audio/x-m4a",
+  "image/heic",
+  "image/heic-sequence",
+  "image/heif",
+  "image/heif-sequence",
+  "image/avif",
+  "application/octet-stream",
+];
+
+// Brands that identify a format by themselves
+const BRANDS: &[(&str, &str)] = &[
+  ("avif", "image/avif"),
+  ("avis", "image/avif"),
+  ("heic", "image/heic"),
+  ("heix", "image/heic"),
+  ("heim", "image/heic"),
+  ("heis", "image/heic"),
+  ("hevc", "image/heic-sequence"),
+  ("hevx", "image/heic-sequence"),
+  ("crx ", "image/x-canon-cr3"),
+  ("qt  ", "video/quicktime"),
+  ("M4A ", "audio/mp4"),
+  ("M4P ", "audio/mp4"),
+  ("M4B ", "audio/x-m4b"),
+  ("M4V ", "video/x-m4v"),
+  ("M4VH", "video/x-m4v"),
+  ("M4VP", "video/x-m4v"),
+  ("f4v ", "video/x-f4v"),
+];
+
+// HEIF brands that are also used by AVIF and HEIC, so only count if nothing
+// more specific is found
+const HEIF_BRANDS: &[(&str, &str)] = &[
+  ("mif1", "image/heif"),
+  ("msf1", "image/heif-sequence"),
+];
+
+const BOX_LEN:       u64 = 8;
+const LARGE_BOX_LEN: u64 = 16;
+const MAX_FTYP_LEN:  u64 = 1024;
+// Fragmented files may have lots of boxes, "moov" is usually early on
+const MAX_BOXES:     usize = 1024;
+
+fn brand_mime(brand: &str) -> Option<&'static str> {
+  if let Some((_, mime)) = BRANDS.iter().find(|(b, _)| *b == brand) {
+    return Some(mime);
+  }
+
+  // 3GPP brands have a variant for each release and profile
+  if brand.starts_with("3g2") {
+    Some("video/3gpp2")
+  } else if brand.starts_with("3g") {
+    Some("video/3gpp")
+  } else {
+    None
+  }
+}
+
+// Type, payload offset and end offset of each box in [start, end)
+fn boxes(c: &Content, start: u64, end: u64) -> io::Result<Vec<(String, u64, u64)>> {
+  let mut result: Vec<(String, u64, u64)> = vec![];
+  let mut offset = start;
+
+  while offset + BOX_LEN <= end && result.len() < MAX_BOXES {
+    let header = c.read_at(offset, LARGE_BOX_LEN)?;
+
+    let (size, kind) = match (be_u32(&header, 0), header.get(4..8)) {
+      (Some(s), Some(k)) => (s, String::from_utf8_lossy(k).into_owned()),
+      _                  => break,
+    };
+
+    let (header_len, size) = match size {
+      0 => (BOX_LEN, end - offset),
+      1 => match be_u64(&header, 8) {
+        Some(s) => (LARGE_BOX_LEN, s),
+        None    => break,
+      },
+      s => (BOX_LEN, s as u64),
+    };
+
+    if size < header_len || offset.saturating_add(size) > end {
+      break;
+    }
+
+    result.push((kind, offset + header_len, offset + size));
+    offset += size;
+  }
+
+  Ok(result)
+}
+
+fn child(c: &Content, (start, end): (u64, u64), kind: &str) -> io::Result<Option<(u64, u64)>> {
+  Ok(boxes(c, start, end)?.into_iter().find(|(k, _, _)| k == kind).map(|(_, s, e)| (s, e)))
+}
+
+// Handler types of all tracks, e.g. "vide" or "soun"
+fn handlers(c: &Content, moov: (u64, u64)) -> io::Result<Vec<String>> {
+  let mut result: Vec<String> = vec![];
+
+  for (kind, start, end) in boxes(c, moov.0, moov.1)? {
+    if kind != "trak" {
+      continue;
+    }
+
+    let hdlr = match child(c, (start, end), "mdia")? {
+      Some(mdia) => child(c, mdia, "hdlr")?,
+      None       => None,
+    };
+
+    if let Some((start, _)) = hdlr {
+      let payload = c.read_at(start, 12)?;
+
+      if let Some(h) = payload.get(8..12) {
+        result.push(String::from_utf8_lossy(h).into_owned());
+      }
+    }
+  }
+
+  Ok(result)
+}
+
+pub fn sniff(c: &Content, _mime: &str) -> io::Result<Option<Sniffed>> {
+  let top = boxes(c, 0, c.len)?;
+
+  let ftyp = match top.first() {
+    Some((kind, start, end)) if kind == "ftyp" => {
+      c.read_at(*start, (end - start).min(MAX_FTYP_LEN))?
+    }
+    _ => return Ok(None),
+  };
+
+  // The major brand, then the compatible ones, skipping the minor version
+  let brands: Vec<String> = ftyp
+    .chunks_exact(4)
+    .enumerate()
+    .filter(|(i, _)| *i != 1)
+    .map(|(_, b)| String::from_utf8_lossy(b).into_owned())
+    .collect();
+
+  if brands.is_empty() {
+    return Ok(None);
+  }
+
+  let mut detail = format!(
+    "ftyp {} ({})",
+    brands[0].trim_end(),
+    brands[1..].iter().map(|b| b.trim_end()).collect::<Vec<&str>>().join(" ")
+  );
+
+  let heif_mime = |brand: &String| HEIF_BRANDS.iter().find(|(h, _)| h == brand).map(|(_, m)| *m);
+
+  let specific = brands
+    .iter()
+    .find_map(|b| brand_mime(b))
+    .or_else(|| brands.iter().find_map(heif_mime));
+
+  let mime = match specific {
+    Some(mime) => mime,
+    None       => {
+      let moov = top.iter().find(|(k, _, _)| k == "moov").map(|(_, s, e)| (*s, *e));
+
+      let handlers = match moov {
+        Some(moov) => handlers(c, moov)?,
+        None       => vec![],
+      };
+
+      if !handlers.is_empty() {
+        detail += &format!(", handlers {}", handlers.join(" "));
+      }
+
+      if !handlers.iter().any(|h| h == "vide") && handlers.iter().any(|h| h == "soun") {
+        "audio/mp4"
+      } else {
+        "video/mp4"
+      }
+    }
+  };
+
+  Ok(Some(Sniffed { mime: mime.to_string(), detail }))
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn bx(kind: &str, payload: &[u8]) -> Vec<u8> {
+    let mut b: Vec<u8> = vec![];
+    b.extend(&(BOX_LEN as u32 + payload.len() as u32).to_be_bytes());
+    b.extend(kind.as_bytes());
+    b.extend(payload);
+    b
+  }
+
+  fn ftyp(major: &str, compatible: &[&str]) -> Vec<u8> {
+    let mut payload: Vec<u8> = major.as_bytes().to_vec();
+    payload.extend(&[0, 0, 0, 0]);
+    compatible.iter().for_each(|b| payload.extend(b.as_bytes()));
+    bx("ftyp", &payload)
+  }
+
+  // A "trak" box with just a handler type
+  fn trak(handler: &str) -> Vec<u8> {
+    let mut hdlr: Vec<u8> = vec![0; 8];
+    hdlr.extend(handler.as_bytes());
+    bx("trak", &bx("mdia", &bx("hdlr", &hdlr)))
+  }
+
+  fn sniffed(b: &[u8]) -> Option<String> {
+    sniff(&Content::from_bytes(b), "video/mp4").unwrap().map(|s| s.mime)
+  }
+
+  #[test]
+  fn brands() {
+    let mime = |major, compatible: &[&str]| sniffed(&ftyp(major, compatible));
+
+    assert_eq!(mime("avif", &["mif1", "miaf"]).as_deref(), Some("image/avif"));
+    assert_eq!(mime("mif1", &["mif1", "heic"]).as_deref(), Some("image/heic"));
+    assert_eq!(mime("mif1", &["mif1", "miaf"]).as_deref(), Some("image/heif"));
+    assert_eq!(mime("qt  ", &[]).as_deref(), Some("video/quicktime"));
+    assert_eq!(mime("M4A ", &["isom"]).as_deref(), Some("audio/mp4"));
+    assert_eq!(mime("3gp5", &["isom"]).as_deref(), Some("video/3gpp"));
+    assert_eq!(mime("3g2a", &[]).as_deref(), Some("video/3gpp2"));
+  }
+
+  #[test]
+  fn generic_brands_go_by_tracks() {
+    let with_tracks = |tracks: &[&str]| {
+      let mut b = ftyp("isom", &["isom", "mp41"]);
+      b.extend(bx("moov", &tracks.iter().flat_map(|h| trak(h)).collect::<Vec<u8>>()));
+      sniffed(&b)
+    };
+
+    assert_eq!(with_tracks(&["soun"]).as_deref(), Some("audio/mp4"));
+    assert_eq!(with_tracks(&["soun", "vide"]).as_deref(), Some("video/mp4"));
+    assert_eq!(with_tracks(&[]).as_deref(), Some("video/mp4"));
+  }
+
+  #[test]
+  fn box_sizes() {
+    // A 64-bit size, and a size of 0 for the rest of the file
+    let mut large = vec![0, 0, 0, 1];
+    large.extend(b"ftyp");
+    large.extend(&(LARGE_BOX_LEN + 8).to_be_bytes());
+    large.extend(b"heic\0\0\0\0");
+    assert_eq!(sniffed(&large).as_deref(), Some("image/heic"));
+
+    let mut rest = vec![0, 0, 0, 0];
+    rest.extend(b"ftypavif\0\0\0\0");
+    assert_eq!(sniffed(&rest).as_deref(), Some("image/avif"));
+
+    // Not an ISO base media file, or a box that goes past the end
+    assert_eq!(sniffed(&bx("moov", &[])), None);
+    assert_eq!(sniffed(&ftyp("avif", &[])[..12]), None);
+  }
+}
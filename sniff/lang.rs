@@ -0,0 +1,341 @@
+// Source code that libmagic calls plain text (or the wrong language): a vim or
+// Emacs modeline settles it, otherwise each language scores points for the
+// keywords and syntax it has, and the confidence is how far ahead the best
+// one is of half the runner-up, as languages share some syntax
+
+use regex::Regex;
+
+use super::Content;
+use super::Sniffed;
+
+
+// libmagic mistakes e.g. Rust and Go for C or Algol 68, and TypeScript for
+// JavaScript
+pub const MIMES: &[&str] = &[
+  "text/plain",
+  "application/javascript",
+  "text/x-c",
+  "text/x-c++",
+  "text/x-java",
+  "text/x-objective-c",
+  "text/x-Algol68",
+  "text/x-algol68",
+];
+
+const MAX_TEXT_LEN: u64 = 64 << 10;
+// Modelines are only looked for in this many first and last lines
+const MODELINE_LINES: usize = 5;
+const MAX_SCORE: u32 = 100;
+
+struct Language {
+  name:  &'static str,
+  mime:  &'static str,
+  // Vim filetypes and Emacs modes
+  modes: &'static [&'static str],
+  // Multiline regexes and their points, each counted once
+  rules: &'static [(&'static str, u32)],
+}
+
+const LANGUAGES: &[Language] = &[
+  Language {
+    name:  "rust",
+    mime:  "text/x-rust",
+    modes: &["rust", "rs"],
+    rules: &[
+      (r"\bfn\s+\w+\s*(<[^>]*>)?\s*\(", 30),
+      (r"\blet\s+(mut\s+)?\w+", 15),
+      (r"^\s*(pub(\(crate\))?\s+)?(struct|enum|trait|impl|mod)\b", 25),
+      (r"^\s*use\s+\w+(::\w+)+", 30),
+      (r"#!?\[(derive|cfg|test|allow|feature)\b", 30),
+      (r"\b(println|vec|format|macro_rules|assert_eq)!", 30),
+      (r"->\s*(Result|Option|Self|&|impl\b)", 20),
+    ],
+  },
+  Language {
+    name:  "c",
+    mime:  "text/x-c",
+    modes: &["c"],
+    rules: &[
+      (r#"^\s*#\s*include\s*[<"]\w+\.h[>"]"#, 40),
+      (r"\bint\s+main\s*\(", 30),
+      (r"\b(printf|fprintf|malloc|free|memcpy|sizeof)\s*\(", 20),
+      (r"^\s*typedef\s+", 20),
+      (r"^\s*#\s*(define|ifdef|ifndef|endif)\b", 20),
+    ],
+  },
+  Language {
+    name:  "c++",
+    mime:  "text/x-c++",
+    modes: &["cpp", "c++"],
+    rules: &[
+      (r"^\s*#\s*include\s*<[a-z_]+>", 40),
+      (r"\bstd::(string|vector|map|cout|cerr|endl|unique_ptr|move)\b", 30),
+      (r"^\s*(template\s*<|namespace\s+\w+|using\s+namespace\b)", 30),
+      (r"\b(public|private|protected):", 20),
+      (r"\b(cout|cerr)\s*<<", 20),
+    ],
+  },
+  Language {
+    name:  "python",
+    mime:  "text/x-python",
+    modes: &["python", "py"],
+    rules: &[
+      (r"^\s*def\s+\w+\s*\(.*\)\s*(->.*)?:\s*$", 30),
+      (r"^\s*(import\s+[\w.]+|from\s+[\w.]+\s+import\b)", 30),
+      (r"^\s*class\s+\w+(\(.*\))?:\s*$", 20),
+      (r"\bself\.\w+", 20),
+      (r#"__name__\s*==\s*['"]__main__['"]"#, 40),
+      (r"^\s*(elif\b.*|else|try|except\b.*|finally):\s*$", 20),
+    ],
+  },
+  Language {
+    name:  "go",
+    mime:  "text/x-go",
+    modes: &["go", "golang"],
+    rules: &[
+      (r"^package\s+\w+\s*$", 40),
+      (r#"^import\s+(\(|")"#, 20),
+      (r"^func\s+(\(\w+\s+\*?\w+\)\s*)?\w+\s*\(", 30),
+      (r"\w\s*:=\s*", 20),
+      (r"\bfmt\.\w+\(", 20),
+      (r"\b(go\s+func|chan\s+\w+|defer\s+\w+)", 20),
+    ],
+  },
+  Language {
+    name:  "javascript",
+    mime:  "application/javascript",
+    modes: &["javascript", "js", "js2"],
+    rules: &[
+      (r"^\s*(const|let|var)\s+\w+\s*=", 15),
+      (r"\bfunction\s*\w*\s*\(", 25),
+      (r"\)\s*=>\s*[{(]?", 15),
+      (r"\b(console\.log|document\.|window\.|require\(|module\.exports)", 30),
+      (r#"^\s*(import\s+.*\s+from\s+['"]|export\s+(default|const|function)\b)"#, 30),
+      (r"===|!==", 20),
+    ],
+  },
+  Language {
+    name:  "typescript",
+    mime:  "text/x-typescript",
+    modes: &["typescript", "ts"],
+    rules: &[
+      (r"^\s*(export\s+)?(interface|type)\s+\w+(<.*>)?\s*[={]", 50),
+      (r"\w\??:\s*(string|number|boolean|any|unknown|void)\b", 40),
+      (r"\)\s*:\s*[\w<>\[\]]+\s*(=>|\{)", 30),
+      (r"^\s*import\s+type\b", 30),
+    ],
+  },
+  Language {
+    name:  "sh",
+    mime:  "text/x-shellscript",
+    modes: &["sh", "bash", "zsh", "ksh", "shell-script"],
+    rules: &[
+      (r"^\s*(if|while|until)\s+\[\[?\s", 30),
+      (r"^\s*(fi|done|esac)\s*$", 30),
+      (r"^\s*(export|local|readonly)\s+\w+=", 20),
+      (r"^\s*\w+=\S*\$[({]", 20),
+      (r"\$\{\w+(:[-=?+][^}]*)?\}", 15),
+      (r"^\s*(echo|printf)\s", 10),
+    ],
+  },
+  Language {
+    name:  "ruby",
+    mime:  "text/x-ruby",
+    modes: &["ruby", "rb"],
+    rules: &[
+      (r"^\s*def\s+[\w.]+[?!]?(\(.*\))?\s*$", 20),
+      (r"^\s*end\s*$", 20),
+      (r#"^\s*require(_relative)?\s+['"]"#, 20),
+      (r"\.each(_with_index)?\s+do\s*\|", 30),
+      (r"^\s*attr_(accessor|reader|writer)\b", 30),
+      (r"^\s*puts\s", 20),
+    ],
+  },
+  Language {
+    name:  "perl",
+    mime:  "text/x-perl",
+    modes: &["perl", "cperl"],
+    rules: &[
+      (r"^\s*use\s+(strict|warnings);", 40),
+      (r"\bmy\s+[$@%]\w+", 30),
+      (r"(\$_\b|@ARGV|\$0\b)", 20),
+      (r"=~\s*[ms]?/", 20),
+    ],
+  },
+  Language {
+    name:  "java",
+    mime:  "text/x-java",
+    modes: &["java"],
+    rules: &[
+      (r"\bpublic\s+(static\s+|final\s+|abstract\s+)*(class|interface|void)\b", 30),
+      (r"\bSystem\.(out|err)\.print", 30),
+      (r"^import\s+(static\s+)?[\w.]+\*?;", 30),
+      (r"^package\s+[\w.]+;", 30),
+      (r"^\s*@Override\b", 20),
+    ],
+  },
+  Language {
+    name:  "lua",
+    mime:  "text/x-lua",
+    modes: &["lua"],
+    rules: &[
+      (r"\blocal\s+(function\s+)?\w+", 30),
+      (r"\bthen\s*$", 20),
+      (r"--\[\[", 20),
+      (r"\b~=", 10),
+    ],
+  },
+  Language {
+    name:  "php",
+    mime:  "text/x-php",
+    modes: &["php"],
+    rules: &[
+      (r"<\?php\b", 80),
+      (r"\$this->", 30),
+    ],
+  },
+];
+
+thread_local! {
+  static RULES: Vec<Vec<(Regex, u32)>> = LANGUAGES
+    .iter()
+    .map(|l| {
+      l.rules
+        .iter()
+        .map(|(r, points)| (Regex::new(&format!("(?m){}", r)).unwrap(), *points))
+        .collect()
+    })
+    .collect();
+
+  static VIM: Regex =
+    Regex::new(r"(^|\s)(vim?|ex):.*\b(ft|filetype|syntax)=([\w+#-]+)").unwrap();
+
+  static EMACS: Regex =
+    Regex::new(r"(?i)-\*-\s*(mode:\s*)?([\w+#-]+)\s*(;.*)?-\*-").unwrap();
+}
+
+fn by_mode(mode: &str) -> Option<&'static Language> {
+  let mode = mode.to_lowercase();
+  LANGUAGES.iter().find(|l| l.modes.contains(&mode.as_str()))
+}
+
+fn modeline(t: &str) -> Option<Sniffed> {
+  let lines: Vec<&str> = t.lines().collect();
+  let tail = lines.len().saturating_sub(MODELINE_LINES);
+
+  for l in lines.iter().take(MODELINE_LINES).chain(lines.iter().skip(tail)) {
+    let vim = VIM.with(|r| r.captures(l).map(|c| (c[4].to_string(), "vim")));
+    let emacs = EMACS.with(|r| r.captures(l).map(|c| (c[2].to_string(), "Emacs")));
+
+    if let Some((mode, editor)) = vim.or(emacs) {
+      if let Some(lang) = by_mode(&mode) {
+        return Some(Sniffed {
+          mime:   lang.mime.to_string(),
+          detail: format!("{} modeline {}, confidence {}", editor, mode, MAX_SCORE),
+        });
+      }
+    }
+  }
+
+  None
+}
+
+pub fn is_language(mime: &str) -> bool {
+  LANGUAGES.iter().any(|l| l.mime == mime)
+}
+
+// Sniff a source language if the confidence is at least `threshold`
+pub fn sniff(c: &Content, threshold: u32) -> Option<Sniffed> {
+  let t = String::from_utf8_lossy(c.head(MAX_TEXT_LEN));
+
+  if let Some(s) = modeline(&t) {
+    return Some(s);
+  }
+
+  let mut scores: Vec<(u32, &Language)> = RULES.with(|rules| {
+    rules
+      .iter()
+      .zip(LANGUAGES)
+      .map(|(rules, lang)| {
+        let points: u32 = rules.iter().filter(|(r, _)| r.is_match(&t)).map(|(_, p)| p).sum();
+        (points.min(MAX_SCORE), lang)
+      })
+      .collect()
+  });
+
+  scores.sort_by(|a, b| b.0.cmp(&a.0));
+
+  let (best, lang) = scores[0];
+  let confidence = best - scores[1].0 / 2;
+
+  if confidence == 0 || confidence < threshold {
+    return None;
+  }
+
+  Some(Sniffed {
+    mime:   lang.mime.to_string(),
+    detail: format!("{} source, confidence {}", lang.name, confidence),
+  })
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn sniffed(t: &str, threshold: u32) -> Option<Sniffed> {
+    sniff(&Content::from_bytes(t.as_bytes()), threshold)
+  }
+
+  fn mime(t: &str) -> Option<String> {
+    sniffed(t, 60).map(|s| s.mime)
+  }
+
+  #[test]
+  fn modelines() {
+    let s = sniffed("x = 1\n\n# vim: set ft=python ts=4:\n", 60).unwrap();
+    assert_eq!(s.mime, "text/x-python");
+    assert_eq!(s.detail, "vim modeline python, confidence 100");
+
+    let emacs = "// -*- mode: Rust; indent-tabs-mode: nil -*-\n";
+    assert_eq!(mime(emacs).as_deref(), Some("text/x-rust"));
+    assert_eq!(mime("/* -*- c++ -*- */\n").as_deref(), Some("text/x-c++"));
+
+    // Unknown modes, and modelines in the middle of the file, are no help
+    assert_eq!(mime("# vim: ft=cobol\n"), None);
+    let middle = format!("{}vim: ft=rust\n{}", "text\n".repeat(6), "text\n".repeat(6));
+    assert_eq!(mime(&middle), None);
+  }
+
+  #[test]
+  fn scores() {
+    let rust = "use std::io::Read;\n\n#[derive(Debug)]\npub struct A;\n\n\
+                fn main() -> Result<(), ()> {\n  let mut s = vec![];\n  Ok(())\n}\n";
+    assert_eq!(mime(rust).as_deref(), Some("text/x-rust"));
+
+    let go = "package main\n\nimport \"fmt\"\n\nfunc main() {\n  s := 1\n  fmt.Println(s)\n}\n";
+    assert_eq!(mime(go).as_deref(), Some("text/x-go"));
+
+    let python = "import os\n\nclass A:\n    def f(self):\n        return self.x\n\n\
+                  if __name__ == '__main__':\n    A().f()\n";
+    assert_eq!(mime(python).as_deref(), Some("text/x-python"));
+
+    let c = "#include <stdio.h>\n\nint main(void) {\n  printf(\"hi\\n\");\n}\n";
+    assert_eq!(mime(c).as_deref(), Some("text/x-c"));
+  }
+
+  #[test]
+  fn thresholds() {
+    let prose = "Dear all,\n\nthe meeting is moved to Friday; let me know if that works.\n";
+    assert_eq!(mime(prose), None);
+
+    // "let me know" looks like Rust, but only a little
+    assert_eq!(sniffed(prose, 20), None);
+
+    // A single JavaScript hint is not enough by default
+    let js = "const x = 1\n";
+    assert_eq!(mime(js), None);
+    let detail = sniffed(js, 10).map(|s| s.detail);
+    assert_eq!(detail.as_deref(), Some("javascript source, confidence 15"));
+  }
+}
@@ -0,0 +1,153 @@
+// Ogg: each logical stream starts with a beginning-of-stream page, whose first
+// packet identifies the codec; all of these pages come first
+
+use std::io;
+
+use super::Content;
+use super::Sniffed;
+
+
+pub const MIMES: &[&str] = &[
+  "audio/ogg",
+  "video/ogg",
+  "application/ogg",
+  "audio/x-opus+ogg",
+  "audio/x-vorbis+ogg",
+  "application/octet-stream",
+];
+
+const PAGE_LEN:       usize = 27;
+const BOS_FLAG:       u8 = 0x02;
+const MAX_PREFIX_LEN: u64 = 64 << 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+  Audio,
+  Video,
+  Other,
+}
+
+// First packet prefixes of known codecs
+const CODECS: &[(&[u8], &str, Kind)] = &[
+  (b"OpusHead",    "opus",     Kind::Audio),
+  (b"\x01vorbis",  "vorbis",   Kind::Audio),
+  (b"\x7fFLAC",    "flac",     Kind::Audio),
+  (b"Speex   ",    "speex",    Kind::Audio),
+  (b"\x80theora",  "theora",   Kind::Video),
+  (b"\x80daala",   "daala",    Kind::Video),
+  (b"OVP80",       "vp8",      Kind::Video),
+  (b"BBCD\x00",    "dirac",    Kind::Video),
+  (b"fishead\x00", "skeleton", Kind::Other),
+  (b"\x80kate",    "kate",     Kind::Other),
+];
+
+pub fn sniff(c: &Content, _mime: &str) -> io::Result<Option<Sniffed>> {
+  let b = c.head(MAX_PREFIX_LEN);
+
+  let mut codecs: Vec<(&str, Kind)> = vec![];
+  let mut i = 0;
+
+  while b.get(i..i + 4) == Some(&b"OggS"[..]) && i + PAGE_LEN <= b.len() {
+    if b[i + 5] & BOS_FLAG == 0 {
+      break;
+    }
+
+    let segments = b[i + 26] as usize;
+
+    let body_len: usize = match b.get(i + PAGE_LEN..i + PAGE_LEN + segments) {
+      Some(table) => table.iter().map(|&l| l as usize).sum(),
+      None        => break,
+    };
+
+    let body = i + PAGE_LEN + segments;
+    let packet = &b[body.min(b.len())..(body + body_len).min(b.len())];
+
+    codecs.push(
+      CODECS
+        .iter()
+        .find(|(prefix, _, _)| packet.starts_with(prefix))
+        .map(|(_, name, kind)| (*name, *kind))
+        .unwrap_or(("unknown", Kind::Other)),
+    );
+
+    i = body + body_len;
+  }
+
+  if codecs.is_empty() {
+    return Ok(None);
+  }
+
+  let detail = format!(
+    "codecs {}",
+    codecs.iter().map(|(name, _)| *name).collect::<Vec<&str>>().join(" ")
+  );
+
+  let audio: Vec<&str> = codecs.iter().filter(|(_, k)| *k == Kind::Audio).map(|(n, _)| *n).collect();
+
+  let mime = if codecs.iter().any(|(_, k)| *k == Kind::Video) {
+    "video/ogg"
+  } else if audio.is_empty() {
+    "application/ogg"
+  } else if audio.iter().all(|n| *n == "opus") {
+    "audio/opus"
+  } else if audio.iter().all(|n| *n == "speex") {
+    "audio/x-speex"
+  } else {
+    "audio/ogg"
+  };
+
+  Ok(Some(Sniffed { mime: mime.to_string(), detail }))
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // A page holding one packet, at the beginning of its stream or not
+  fn page(packet: &[u8], bos: bool) -> Vec<u8> {
+    let mut b: Vec<u8> = b"OggS\0".to_vec();
+    b.push(if bos { BOS_FLAG } else { 0 });
+    b.extend(&[0; 20]);
+
+    let mut lacing: Vec<u8> = vec![255; packet.len() / 255];
+    lacing.push((packet.len() % 255) as u8);
+
+    b.push(lacing.len() as u8);
+    b.extend(lacing);
+    b.extend(packet);
+    b
+  }
+
+  fn sniffed(packets: &[&[u8]]) -> Option<Sniffed> {
+    let mut b: Vec<u8> = packets.iter().flat_map(|p| page(p, true)).collect();
+    b.extend(page(b"\x03vorbis comments", false));
+    sniff(&Content::from_bytes(&b), "audio/ogg").unwrap()
+  }
+
+  fn mime(packets: &[&[u8]]) -> Option<String> {
+    sniffed(packets).map(|s| s.mime)
+  }
+
+  #[test]
+  fn codecs() {
+    assert_eq!(mime(&[b"OpusHead\x01\x02"]).as_deref(), Some("audio/opus"));
+    assert_eq!(mime(&[b"\x01vorbis\0\0\0\0"]).as_deref(), Some("audio/ogg"));
+    assert_eq!(mime(&[b"Speex   1.2"]).as_deref(), Some("audio/x-speex"));
+    assert_eq!(mime(&[b"\x80theora", b"\x01vorbis"]).as_deref(), Some("video/ogg"));
+    assert_eq!(mime(&[b"OpusHead", b"Speex   "]).as_deref(), Some("audio/ogg"));
+    assert_eq!(mime(&[b"fishead\0", b"\x80kate"]).as_deref(), Some("application/ogg"));
+  }
+
+  #[test]
+  fn pages() {
+    // Only beginning-of-stream pages count, and packets may span segments
+    let long: Vec<u8> = [&b"OpusHead"[..], &[0; 300]].concat();
+    let s = sniffed(&[&long, b"what is this"]).unwrap();
+    assert_eq!(s.mime, "audio/opus");
+    assert_eq!(s.detail, "codecs opus unknown");
+
+    assert_eq!(mime(&[]), None);
+    assert_eq!(sniff(&Content::from_bytes(b"OggS\0\x02"), "audio/ogg").unwrap(), None);
+  }
+}
@@ -0,0 +1,82 @@
+// Scripts: the #! line names the interpreter, possibly through env(1)
+
+use super::Content;
+
+
+const MAX_LINE_LEN: u64 = 256;
+
+fn basename(command: &str) -> &str {
+  command.rsplit('/').next().unwrap_or(command)
+}
+
+// The command env(1) runs: options (and their arguments) and variable
+// assignments come first, and -S splits its argument like the #! line itself
+fn env_command<'a>(mut args: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+  while let Some(arg) = args.next() {
+    match arg {
+      "-S" | "--split-string" | "-" | "--" | "-i" | "--ignore-environment" => (),
+      "-u" | "--unset" | "-C" | "--chdir" => {
+        args.next();
+      }
+      _ if arg.starts_with("-S") => return Some(&arg[2..]),
+      _ if arg.starts_with('-') || arg.contains('=') => (),
+      _ => return Some(arg),
+    }
+  }
+
+  None
+}
+
+// The interpreter of a script, e.g. "python3.11" for
+// "#!/usr/bin/env -S python3.11 -u"
+pub fn interpreter(c: &Content) -> Option<String> {
+  let b = c.head(MAX_LINE_LEN);
+
+  if !b.starts_with(b"#!") {
+    return None;
+  }
+
+  let line = String::from_utf8_lossy(&b[2..]);
+  let mut args = line.lines().next().unwrap_or_default().split_whitespace();
+
+  let command = match args.next().map(basename) {
+    Some("env") => env_command(args),
+    command     => command,
+  };
+
+  command.map(basename).filter(|c| !c.is_empty()).map(|c| c.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn interp(line: &str) -> Option<String> {
+    interpreter(&Content::from_bytes(line.as_bytes()))
+  }
+
+  #[test]
+  fn direct() {
+    assert_eq!(interp("#!/bin/sh\necho\n").as_deref(), Some("sh"));
+    assert_eq!(interp("#! /usr/bin/perl -w\n").as_deref(), Some("perl"));
+    assert_eq!(interp("#!/usr/bin/make -f\n").as_deref(), Some("make"));
+  }
+
+  #[test]
+  fn through_env() {
+    assert_eq!(interp("#!/usr/bin/env python3\n").as_deref(), Some("python3"));
+    assert_eq!(interp("#!/usr/bin/env -S python3.11 -u\n").as_deref(), Some("python3.11"));
+    assert_eq!(interp("#!/usr/bin/env -Sdeno run\n").as_deref(), Some("deno"));
+    assert_eq!(interp("#!/usr/bin/env -i -u HOME LANG=C node\n").as_deref(), Some("node"));
+    assert_eq!(interp("#!/usr/bin/env -C /tmp -- ruby\n").as_deref(), Some("ruby"));
+  }
+
+  #[test]
+  fn none() {
+    assert_eq!(interp("#!/usr/bin/env\n"), None);
+    assert_eq!(interp("#!/usr/bin/env -i\n"), None);
+    assert_eq!(interp("#!\n"), None);
+    assert_eq!(interp("echo #!/bin/sh\n"), None);
+    assert_eq!(interp(""), None);
+  }
+}
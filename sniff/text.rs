@@ -0,0 +1,519 @@
+// Structured text that libmagic only reports as plain text or generic XML:
+// JSON, XML dialects by their root element, YAML, and guesses of Markdown and
+// CSV/TSV
+
+use std::io;
+
+use regex::Regex;
+
+use super::Content;
+use super::Sniffed;
+use super::lang;
+
+
+pub const MIMES: &[&str] = &[
+  "text/plain",
+  "text/csv",
+  "text/xml",
+  "application/xml",
+  "application/json",
+];
+
+// Markdown and CSV/TSV are only guessed for these, as any source code might
+// look like them; Markdown is also guessed for what libmagic takes for source
+// code, as it does with READMEs full of code blocks
+const PLAIN_MIMES: &[&str] = &["text/plain", "text/csv"];
+
+// What guesses may come up with
+pub const GUESSES: &[&str] = &["text/markdown", "text/csv", "text/tab-separated-values"];
+
+const MAX_TEXT_LEN: u64 = 1 << 20;
+const MAX_DEPTH:    usize = 512;
+
+// Fields longer than this on average are more likely to be prose than data
+const MAX_FIELD_LEN: usize = 64;
+// A header and a couple of rows
+const MIN_CSV_LINES: usize = 3;
+
+const CSV_DELIMITERS: &[(char, &str)] = &[
+  ('\t', "text/tab-separated-values"),
+  (',',  "text/csv"),
+  (';',  "text/csv"),
+  ('|',  "text/csv"),
+];
+
+#[derive(Debug, PartialEq, Eq)]
+enum JsonError {
+  Eof,
+  Syntax,
+}
+
+// A validating JSON parser; running out of input is reported separately, so
+// that files longer than MAX_TEXT_LEN can be accepted
+struct Json<'a> {
+  b: &'a [u8],
+  i: usize,
+}
+
+impl<'a> Json<'a> {
+  fn peek(&mut self) -> Result<u8, JsonError> {
+    while let Some(c) = self.b.get(self.i) {
+      if !b" \t\r\n".contains(c) {
+        return Ok(*c);
+      }
+
+      self.i += 1;
+    }
+
+    Err(JsonError::Eof)
+  }
+
+  fn expect(&mut self, literal: &[u8]) -> Result<(), JsonError> {
+    for c in literal {
+      match self.b.get(self.i) {
+        Some(x) if x == c => self.i += 1,
+        Some(_)           => return Err(JsonError::Syntax),
+        None              => return Err(JsonError::Eof),
+      }
+    }
+
+    Ok(())
+  }
+
+  fn string(&mut self) -> Result<(), JsonError> {
+    self.expect(b"\"")?;
+
+    loop {
+      match self.b.get(self.i) {
+        None                 => return Err(JsonError::Eof),
+        Some(b'"')           => break,
+        Some(b'\\')          => self.i += 1,
+        Some(c) if *c < 0x20 => return Err(JsonError::Syntax),
+        Some(_)              => (),
+      }
+
+      self.i += 1;
+    }
+
+    self.i += 1;
+    Ok(())
+  }
+
+  fn number(&mut self) -> Result<(), JsonError> {
+    let start = self.i;
+
+    while let Some(c) = self.b.get(self.i) {
+      if !b"+-.eE0123456789".contains(c) {
+        break;
+      }
+
+      self.i += 1;
+    }
+
+    let number = String::from_utf8_lossy(&self.b[start..self.i]);
+
+    match number.parse::<f64>() {
+      Ok(_) if !number.starts_with('+') && !number.starts_with('.') => Ok(()),
+      _ if self.i == self.b.len()                                  => Err(JsonError::Eof),
+      _                                                            => Err(JsonError::Syntax),
+    }
+  }
+
+  fn container(&mut self, close: u8, depth: usize) -> Result<(), JsonError> {
+    self.i += 1;
+
+    if self.peek()? == close {
+      self.i += 1;
+      return Ok(());
+    }
+
+    loop {
+      if close == b'}' {
+        self.peek()?;
+        self.string()?;
+
+        if self.peek()? != b':' {
+          return Err(JsonError::Syntax);
+        }
+
+        self.i += 1;
+      }
+
+      self.value(depth + 1)?;
+
+      match self.peek()? {
+        b',' => self.i += 1,
+        c if c == close => {
+          self.i += 1;
+          return Ok(());
+        }
+        _ => return Err(JsonError::Syntax),
+      }
+    }
+  }
+
+  fn value(&mut self, depth: usize) -> Result<(), JsonError> {
+    if depth > MAX_DEPTH {
+      return Err(JsonError::Syntax);
+    }
+
+    match self.peek()? {
+      b'{'               => self.container(b'}', depth),
+      b'['               => self.container(b']', depth),
+      b'"'               => self.string(),
+      b't'               => self.expect(b"true"),
+      b'f'               => self.expect(b"false"),
+      b'n'               => self.expect(b"null"),
+      b'-' | b'0'..=b'9' => self.number(),
+      _                  => Err(JsonError::Syntax),
+    }
+  }
+}
+
+// "application/json" for a single object or array, "application/x-ndjson" for
+// one per line
+fn json(t: &str, truncated: bool) -> Option<Sniffed> {
+  let mut p = Json { b: t.as_bytes(), i: 0 };
+  let mut values = 0;
+
+  loop {
+    match p.peek() {
+      Ok(b'{') | Ok(b'[')               => (),
+      Err(JsonError::Eof) if values > 0 => break,
+      _                                 => return None,
+    }
+
+    let start = p.i;
+
+    match p.value(0) {
+      Ok(())                           => values += 1,
+      Err(JsonError::Eof) if truncated => {
+        values += 1;
+        break;
+      }
+      Err(_)                           => return None,
+    }
+
+    // Values of JSON Lines are on separate lines
+    if values > 1 && !t[..start].trim_end_matches(|c| c == ' ' || c == '\t').ends_with('\n') {
+      return None;
+    }
+  }
+
+  let (mime, detail) = if values == 1 {
+    ("application/json", String::from("valid JSON"))
+  } else {
+    ("application/x-ndjson", format!("{} JSON lines", values))
+  };
+
+  Some(Sniffed { mime: mime.to_string(), detail })
+}
+
+// The text after `end` in `s`, or an empty string
+fn after<'a>(s: &'a str, end: &str) -> &'a str {
+  s.find(end).map_or("", |i| &s[i + end.len()..])
+}
+
+fn xml(t: &str) -> Option<Sniffed> {
+  let mut s = t.trim_start();
+  let declared = s.starts_with("<?xml");
+
+  // Skip the declaration, processing instructions, comments and DOCTYPE
+  loop {
+    s = if s.starts_with("<?") {
+      after(s, "?>")
+    } else if s.starts_with("<!--") {
+      after(s, "-->")
+    } else if s.starts_with("<!") {
+      match (s.find('['), s.find('>')) {
+        (Some(i), Some(j)) if i < j => after(s, "]>"),
+        _                           => after(s, ">"),
+      }
+    } else {
+      break;
+    }.trim_start();
+  }
+
+  if !s.starts_with('<') {
+    return None;
+  }
+
+  let tag = &s[..s.find('>')?];
+  let name: String = tag[1..].chars().take_while(|c| !c.is_whitespace() && *c != '/').collect();
+  let local = name.rsplit(':').next().unwrap_or_default();
+
+  // HTML may have the XHTML namespace too, so require an XML declaration
+  let xhtml = declared && tag.contains("www.w3.org/1999/xhtml");
+
+  let mime = match local {
+    "svg"                                          => "image/svg+xml",
+    "rss"                                          => "application/rss+xml",
+    "RDF" if tag.contains("purl.org/rss")          => "application/rss+xml",
+    "feed" if tag.contains("www.w3.org/2005/Atom") => "application/atom+xml",
+    "plist"                                        => "application/x-plist",
+    "html" if xhtml                                => "application/xhtml+xml",
+    _                                              => return None,
+  };
+
+  Some(Sniffed { mime: mime.to_string(), detail: format!("XML root <{}>", name) })
+}
+
+fn is_yaml_line(l: &str) -> bool {
+  let t = l.trim_start();
+
+  t.is_empty()
+    || t.starts_with('#')
+    || t.starts_with("- ")
+    || t == "-"
+    || t == "---"
+    || t == "..."
+    || l.starts_with(|c: char| c.is_whitespace())
+    || t.split_once(':').map_or(false, |(k, v)| {
+      !k.is_empty() && !k.contains(char::is_whitespace) && (v.is_empty() || v.starts_with(' '))
+    })
+}
+
+// YAML documents with an explicit start, and Markdown with YAML front matter
+fn yaml(t: &str) -> Option<Sniffed> {
+  let mut lines = t.lines();
+  let first = lines.next()?.trim_end();
+
+  if !(first == "---" || first.starts_with("--- ") || first.starts_with("%YAML")) {
+    return None;
+  }
+
+  let rest: Vec<&str> = lines.collect();
+
+  // Front matter is closed by another "---", and followed by the document
+  if first == "---" {
+    if let Some(end) = rest.iter().position(|l| l.trim_end() == "---") {
+      let body = rest[end + 1..].join("\n");
+
+      if rest[..end].iter().all(|l| is_yaml_line(l)) && !body.trim().is_empty() {
+        if body.trim_start().starts_with('<') {
+          return None;
+        }
+
+        return Some(Sniffed {
+          mime:   String::from("text/markdown"),
+          detail: String::from("YAML front matter"),
+        });
+      }
+    }
+  }
+
+  if rest.iter().all(|l| is_yaml_line(l)) && rest.iter().any(|l| l.contains(':') || l.starts_with('-')) {
+    return Some(Sniffed { mime: String::from("application/yaml"), detail: String::from("YAML") });
+  }
+
+  None
+}
+
+fn markdown(t: &str) -> Option<Sniffed> {
+  let lines: Vec<&str> = t.lines().collect();
+  let mut features: Vec<&str> = vec![];
+
+  let mut feature = |name, found: bool| {
+    if found && !features.contains(&name) {
+      features.push(name);
+    }
+  };
+
+  for (i, l) in lines.iter().enumerate() {
+    let hashes = l.chars().take_while(|c| *c == '#').count();
+    let t = l.trim();
+
+    feature("heading", (1..=6).contains(&hashes) && l[hashes..].starts_with(' '));
+    feature("list", ["- ", "* ", "+ "].iter().any(|p| t.starts_with(p)));
+    feature("fence", t.starts_with("```") || t.starts_with("~~~"));
+    feature("link", t.find("](").map_or(false, |j| t[..j].contains('[') && t[j..].contains(')')));
+    feature("emphasis", t.matches("**").count() >= 2 || t.matches('`').count() >= 2);
+    feature("table", t.starts_with('|') && t.ends_with('|') && t.contains("---"));
+
+    let underline = !t.is_empty() && (t.chars().all(|c| c == '=') || t.chars().all(|c| c == '-'));
+    feature("setext", underline && t.len() >= 3 && i > 0 && !lines[i - 1].trim().is_empty());
+  }
+
+  // Comments, lists and inline markup are common in any text, so look for a
+  // block structure as well
+  let blocks = ["heading", "fence", "table", "setext"];
+
+  if features.len() >= 2 && features.iter().any(|f| blocks.contains(f)) {
+    return Some(Sniffed {
+      mime:   String::from("text/markdown"),
+      detail: format!("Markdown {}", features.join(" ")),
+    });
+  }
+
+  None
+}
+
+// Number of fields in a CSV line, or None if a quoted field spans lines
+fn fields(l: &str, delimiter: char) -> Option<usize> {
+  let mut quoted = false;
+  let mut n = 1;
+
+  for c in l.chars() {
+    match c {
+      '"'                            => quoted = !quoted,
+      c if c == delimiter && !quoted => n += 1,
+      _                              => (),
+    }
+  }
+
+  if quoted { None } else { Some(n) }
+}
+
+thread_local! {
+  // E.g. "2024-01-01 12:00:00,123" or "[2024-01-01T12:00:00Z]"
+  static TIMESTAMP: Regex = Regex::new(r"^\[?\d{4}-\d\d-\d\d[ T]\d\d:\d\d").unwrap();
+}
+
+fn csv(t: &str, truncated: bool) -> Option<Sniffed> {
+  let mut lines: Vec<&str> = t.lines().filter(|l| !l.trim().is_empty()).collect();
+
+  if truncated {
+    lines.pop();
+  }
+
+  // Prose with a comma on every line is not a table, and neither is a log
+  // with a comma in its timestamps
+  let sentences = lines.iter().filter(|l| l.trim_end().ends_with(&['.', '!', '?'][..])).count();
+  let logged = TIMESTAMP.with(|r| lines.iter().any(|l| r.is_match(l)));
+
+  if lines.len() < MIN_CSV_LINES || sentences * 2 > lines.len() || logged {
+    return None;
+  }
+
+  for (delimiter, mime) in CSV_DELIMITERS {
+    let counts: Option<Vec<usize>> = lines.iter().map(|l| fields(l, *delimiter)).collect();
+
+    let n = match counts {
+      Some(c) if c[0] >= 2 && c.iter().all(|n| *n == c[0]) => c[0],
+      _                                                    => continue,
+    };
+
+    let chars: usize = lines.iter().map(|l| l.len()).sum();
+
+    if chars / (lines.len() * n) > MAX_FIELD_LEN {
+      continue;
+    }
+
+    return Some(Sniffed {
+      mime:   mime.to_string(),
+      detail: format!("{} columns separated by {:?}", n, delimiter),
+    });
+  }
+
+  None
+}
+
+pub fn sniff(c: &Content, _mime: &str) -> io::Result<Option<Sniffed>> {
+  let b = c.head(MAX_TEXT_LEN);
+  let truncated = c.truncated(b);
+
+  let t = String::from_utf8_lossy(b);
+  let t = t.trim_start_matches('\u{feff}');
+
+  Ok(json(t, truncated).or_else(|| xml(t)).or_else(|| yaml(t)))
+}
+
+// Markdown or CSV/TSV, for text that is nothing more specific
+pub fn guess(c: &Content, mime: &str) -> Option<Sniffed> {
+  let plain = PLAIN_MIMES.contains(&mime);
+
+  if !plain && !lang::MIMES.contains(&mime) {
+    return None;
+  }
+
+  let b = c.head(MAX_TEXT_LEN);
+  let truncated = c.truncated(b);
+
+  let t = String::from_utf8_lossy(b);
+  let t = t.trim_start_matches('\u{feff}');
+
+  markdown(t).or_else(|| if plain { csv(t, truncated) } else { None })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn mime(s: Option<Sniffed>) -> Option<String> {
+    s.map(|s| s.mime)
+  }
+
+  #[test]
+  fn json_values() {
+    let object = "{\"a\": [1, 2.5e3, true, null]}\n";
+    assert_eq!(mime(json(object, false)), Some("application/json".into()));
+    assert_eq!(mime(json("{\"a\": 1}\n{\"a\": 2}\n", false)), Some("application/x-ndjson".into()));
+    assert_eq!(mime(json("[1, 2, 3", true)), Some("application/json".into()));
+    assert_eq!(json("[1, 2, 3", false), None);
+    assert_eq!(json("{\"a\": 1} {\"a\": 2}", false), None);
+    assert_eq!(json("{'a': 1}", false), None);
+    assert_eq!(json("42", false), None);
+  }
+
+  #[test]
+  fn xml_roots() {
+    let svg = "<?xml version=\"1.0\"?>\n<!-- x -->\n<svg xmlns=\"http://www.w3.org/2000/svg\"/>";
+    assert_eq!(mime(xml(svg)), Some("image/svg+xml".into()));
+    let rss = "<rss version=\"2.0\"><channel/></rss>";
+    assert_eq!(mime(xml(rss)), Some("application/rss+xml".into()));
+
+    let xhtml = "<html xmlns=\"http://www.w3.org/1999/xhtml\"><body/></html>";
+    assert_eq!(xml(xhtml), None);
+    assert_eq!(
+      mime(xml(&format!("<?xml version=\"1.0\"?>{}", xhtml))),
+      Some("application/xhtml+xml".into())
+    );
+    assert_eq!(xml("<note><to>x</to></note>"), None);
+  }
+
+  #[test]
+  fn yaml_documents() {
+    assert_eq!(mime(yaml("---\nname: x\nlist:\n  - a\n")), Some("application/yaml".into()));
+    assert_eq!(mime(yaml("---\ntitle: x\n---\n# Hello\n")), Some("text/markdown".into()));
+    assert_eq!(yaml("name: x\n"), None);
+    assert_eq!(yaml("---\nnot yaml at all\n"), None);
+  }
+
+  #[test]
+  fn markdown_needs_block_structure() {
+    let readme = "# Title\n\nSome *text* with a [link](https://example.com).\n\n- one\n- two\n";
+    assert_eq!(mime(markdown(readme)), Some("text/markdown".into()));
+    assert_eq!(mime(markdown("Title\n=====\n\n```\ncode\n```\n")), Some("text/markdown".into()));
+
+    // Rust doc comments have links and code spans, but no blocks of their own
+    let rust = "//! See the [docs](https://docs.rs) for `Foo`.\n\
+                /// # Examples\n\
+                /// ```\n\
+                /// let x = **y;\n\
+                /// ```\n\
+                fn main() { println!(\"**{}**\", \"`x`\"); }\n";
+    assert_eq!(markdown(rust), None);
+    assert_eq!(markdown("Some text with [a link](x) and `code`.\n"), None);
+  }
+
+  #[test]
+  fn csv_tables() {
+    let csv_text = "name,age,city\nalice,30,Paris\nbob,25,Rome\n";
+    assert_eq!(mime(csv(csv_text, false)), Some("text/csv".into()));
+    assert_eq!(mime(csv("a\tb\n1\t2\n3\t4\n", false)), Some("text/tab-separated-values".into()));
+    assert_eq!(mime(csv("\"a, b\",c\n1,2\n3,4\n", false)), Some("text/csv".into()));
+  }
+
+  #[test]
+  fn csv_rejects_logs_and_prose() {
+    let log = "2024-01-01 12:00:00,123 INFO starting\n\
+               2024-01-01 12:00:01,456 INFO listening on port 80\n\
+               2024-01-01 12:00:02,789 WARNING slow request\n";
+    assert_eq!(csv(log, false), None);
+
+    // Too few lines, uneven columns, and sentences
+    assert_eq!(csv("a,b\n1,2\n", false), None);
+    assert_eq!(csv("a,b\n1,2,3\n4,5\n", false), None);
+    assert_eq!(csv("Hello, world.\nYes, really.\nNo, not you.\n", false), None);
+  }
+}
@@ -0,0 +1,270 @@
+// TIFF-based camera RAW formats: some have their own signature, DNG has a
+// DNGVersion tag, and the rest are told apart by the Make tag of their first
+// IFD, as long as it looks like a RAW one (with sub-IFDs or a thumbnail)
+
+use std::io;
+
+use super::Content;
+use super::Sniffed;
+use super::be_u16;
+use super::be_u32;
+use super::le_u16;
+use super::le_u32;
+
+
+pub const MIMES: &[&str] = &[
+  "image/tiff",
+  "image/x-canon-cr2",
+  "image/x-olympus-orf",
+  "image/x-panasonic-rw2",
+  "image/x-adobe-dng",
+  "application/octet-stream",
+];
+
+const TIFF_MAGIC: u16 = 42;
+const ORF_MAGICS: &[u16] = &[0x4f52, 0x5352];
+const RW2_MAGIC:  u16 = 0x55;
+
+const NEWSUBFILETYPE_TAG: u16 = 0x00fe;
+const MAKE_TAG:           u16 = 0x010f;
+const MODEL_TAG:          u16 = 0x0110;
+const SUBIFDS_TAG:        u16 = 0x014a;
+const DNGVERSION_TAG:     u16 = 0xc612;
+
+const SHORT_TYPE: u16 = 3;
+
+const HEADER_LEN:    u64 = 16;
+const ENTRY_LEN:     u64 = 12;
+const MAX_ENTRIES:   u64 = 1024;
+const MAX_ASCII_LEN: u64 = 256;
+
+// Make tag prefixes (upper case) of RAW formats without their own signature
+const VENDORS: &[(&str, &str)] = &[
+  ("NIKON",         "image/x-nikon-nef"),
+  ("SONY",          "image/x-sony-arw"),
+  ("PENTAX",        "image/x-pentax-pef"),
+  ("OLYMPUS",       "image/x-olympus-orf"),
+  ("OM DIGITAL",    "image/x-olympus-orf"),
+  ("PANASONIC",     "image/x-panasonic-rw2"),
+  ("SAMSUNG",       "image/x-samsung-srw"),
+  ("HASSELBLAD",    "image/x-hasselblad-3fr"),
+  ("PHASE ONE",     "image/x-phaseone-iiq"),
+  ("KODAK",         "image/x-kodak-dcr"),
+  ("EASTMAN KODAK", "image/x-kodak-dcr"),
+  ("MAMIYA",        "image/x-mamiya-mef"),
+  ("SEIKO EPSON",   "image/x-epson-erf"),
+];
+
+// RAW files from vendors not listed above
+const UNKNOWN_RAW: &str = "image/x-dcraw";
+
+struct Entry {
+  tag:   u16,
+  count: u32,
+  value: u32,
+  raw:   Vec<u8>,
+}
+
+pub fn sniff(c: &Content, _mime: &str) -> io::Result<Option<Sniffed>> {
+  let header = c.read_at(0, HEADER_LEN)?;
+
+  let le = match header.get(0..2) {
+    Some(b"II") => true,
+    Some(b"MM") => false,
+    _           => return Ok(None),
+  };
+
+  let u16_at = |b: &[u8], i: usize| if le { le_u16(b, i) } else { be_u16(b, i) };
+  let u32_at = |b: &[u8], i: usize| if le { le_u32(b, i) } else { be_u32(b, i) };
+
+  macro_rules! sniffed {
+    ($mime:expr, $detail:expr) => {
+      return Ok(Some(Sniffed { mime: $mime.to_string(), detail: $detail.to_string() }))
+    };
+  }
+
+  let magic = u16_at(&header, 2).unwrap_or_default();
+
+  if le && ORF_MAGICS.contains(&magic) {
+    sniffed!("image/x-olympus-orf", "ORF signature");
+  }
+
+  if le && magic == RW2_MAGIC {
+    sniffed!("image/x-panasonic-rw2", "RW2 signature");
+  }
+
+  if magic != TIFF_MAGIC {
+    return Ok(None);
+  }
+
+  if header.get(8..11) == Some(&b"CR\x02"[..]) {
+    sniffed!("image/x-canon-cr2", "CR2 signature");
+  }
+
+  let ifd = match u32_at(&header, 4) {
+    Some(o) => o as u64,
+    None    => return Ok(None),
+  };
+
+  let count = c.read_at(ifd, 2)?;
+  let count = (u16_at(&count, 0).unwrap_or_default() as u64).min(MAX_ENTRIES);
+  let table = c.read_at(ifd + 2, count * ENTRY_LEN)?;
+
+  let entries: Vec<Entry> = table
+    .chunks_exact(ENTRY_LEN as usize)
+    .map(|e| Entry {
+      tag:   u16_at(e, 0).unwrap(),
+      count: u32_at(e, 4).unwrap(),
+      value: match u16_at(e, 2).unwrap() {
+        SHORT_TYPE => u16_at(e, 8).unwrap() as u32,
+        _          => u32_at(e, 8).unwrap(),
+      },
+      raw:   e[8..12].to_vec(),
+    })
+    .collect();
+
+  let find = |tag: u16| entries.iter().find(|e| e.tag == tag);
+
+  if let Some(e) = find(DNGVERSION_TAG) {
+    let version: Vec<String> = e.raw.iter().map(|b| b.to_string()).collect();
+    sniffed!("image/x-adobe-dng", format!("DNGVersion {}", version.join(".")));
+  }
+
+  // ASCII values of up to 4 bytes are stored in the entry itself
+  let ascii = |e: &Entry| -> io::Result<String> {
+    let data = if e.count <= 4 {
+      e.raw[..e.count as usize].to_vec()
+    } else {
+      c.read_at(e.value as u64, (e.count as u64).min(MAX_ASCII_LEN))?.to_vec()
+    };
+
+    Ok(String::from_utf8_lossy(&data).trim_end_matches('\0').trim().to_string())
+  };
+
+  let make = match find(MAKE_TAG) {
+    Some(e) => ascii(e)?,
+    None    => return Ok(None),
+  };
+
+  let detail = match find(MODEL_TAG) {
+    Some(e) => format!("Make {}, Model {}", make, ascii(e)?),
+    None    => format!("Make {}", make),
+  };
+
+  // Plain TIFF images may have a Make tag too, but a single full-size image
+  let thumbnail = find(NEWSUBFILETYPE_TAG).map_or(false, |e| e.value & 1 == 1);
+
+  if find(SUBIFDS_TAG).is_none() && !thumbnail {
+    return Ok(None);
+  }
+
+  let upper = make.to_uppercase();
+
+  let mime = VENDORS
+    .iter()
+    .find(|(vendor, _)| upper.starts_with(vendor))
+    .map_or(UNKNOWN_RAW, |(_, mime)| mime);
+
+  sniffed!(mime, detail);
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const ASCII_TYPE: u16 = 2;
+  const LONG_TYPE:  u16 = 4;
+
+  // A TIFF file with one IFD; ASCII values that do not fit in an entry go
+  // after it
+  fn tiff(le: bool, entries: &[(u16, u16, &[u8])]) -> Vec<u8> {
+    let u16_bytes = |v: u16| if le { v.to_le_bytes() } else { v.to_be_bytes() };
+    let u32_bytes = |v: u32| if le { v.to_le_bytes() } else { v.to_be_bytes() };
+
+    let mut b: Vec<u8> = if le { b"II".to_vec() } else { b"MM".to_vec() };
+    b.extend(&u16_bytes(TIFF_MAGIC));
+    b.extend(&u32_bytes(8));
+    b.extend(&u16_bytes(entries.len() as u16));
+
+    let mut data: Vec<u8> = vec![];
+    let data_start = 8 + 2 + entries.len() * ENTRY_LEN as usize + 4;
+
+    for (tag, kind, value) in entries {
+      let count = if *kind == ASCII_TYPE { value.len() } else { 1 };
+
+      b.extend(&u16_bytes(*tag));
+      b.extend(&u16_bytes(*kind));
+      b.extend(&u32_bytes(count as u32));
+
+      if value.len() <= 4 {
+        b.extend(value.iter().chain(&[0; 4]).take(4));
+      } else {
+        b.extend(&u32_bytes((data_start + data.len()) as u32));
+        data.extend(*value);
+      }
+    }
+
+    b.extend(&[0; 4]);
+    b.extend(data);
+    b
+  }
+
+  fn sniffed(b: &[u8]) -> Option<Sniffed> {
+    sniff(&Content::from_bytes(b), "image/tiff").unwrap()
+  }
+
+  fn mime(b: &[u8]) -> Option<String> {
+    sniffed(b).map(|s| s.mime)
+  }
+
+  #[test]
+  fn signatures() {
+    assert_eq!(mime(b"IIRO\x08\0\0\0").as_deref(), Some("image/x-olympus-orf"));
+    assert_eq!(mime(b"IIU\0\x18\0\0\0").as_deref(), Some("image/x-panasonic-rw2"));
+    assert_eq!(mime(b"II*\0\x10\0\0\0CR\x02\0").as_deref(), Some("image/x-canon-cr2"));
+
+    // Not TIFF at all
+    assert_eq!(mime(b"MMRO\0\0\0\x08"), None);
+    assert_eq!(mime(b"GIF89a"), None);
+  }
+
+  #[test]
+  fn dng_version() {
+    let s = sniffed(&tiff(true, &[(DNGVERSION_TAG, 1, &[1, 4, 0, 0])])).unwrap();
+    assert_eq!(s.mime, "image/x-adobe-dng");
+    assert_eq!(s.detail, "DNGVersion 1.4.0.0");
+  }
+
+  #[test]
+  fn raw_vendors() {
+    let subifds = (SUBIFDS_TAG, LONG_TYPE, &[0x40, 0, 0, 0][..]);
+    let thumbnail = (NEWSUBFILETYPE_TAG, SHORT_TYPE, &[0, 1][..]);
+
+    let nikon = tiff(false, &[(MAKE_TAG, ASCII_TYPE, b"NIKON CORPORATION\0"), subifds]);
+    assert_eq!(mime(&nikon).as_deref(), Some("image/x-nikon-nef"));
+
+    let sony = tiff(true, &[(MAKE_TAG, ASCII_TYPE, b"SONY\0"), (MODEL_TAG, ASCII_TYPE, b"A7\0")]);
+    assert_eq!(mime(&sony), None);
+
+    let sony = tiff(
+      true,
+      &[(NEWSUBFILETYPE_TAG, SHORT_TYPE, &[1, 0]), (MAKE_TAG, ASCII_TYPE, b"SONY\0")],
+    );
+    let s = sniffed(&sony).unwrap();
+    assert_eq!(s.mime, "image/x-sony-arw");
+    assert_eq!(s.detail, "Make SONY");
+
+    let leica = tiff(false, &[(MAKE_TAG, ASCII_TYPE, b"Leica Camera AG\0"), thumbnail]);
+    assert_eq!(mime(&leica).as_deref(), Some("image/x-dcraw"));
+  }
+
+  #[test]
+  fn plain_tiff() {
+    // A full-size image of some camera, or without a Make tag
+    let full_size = (NEWSUBFILETYPE_TAG, SHORT_TYPE, &[0, 0][..]);
+    let photo = tiff(true, &[full_size, (MAKE_TAG, ASCII_TYPE, b"NIKON\0")]);
+    assert_eq!(mime(&photo), None);
+    assert_eq!(mime(&tiff(true, &[(SUBIFDS_TAG, LONG_TYPE, &[0x40, 0, 0, 0])])), None);
+  }
+}
@@ -0,0 +1,341 @@
+// ZIP-based formats: ODF and EPUB declare their MIME type in a stored
+// "mimetype" member, the rest are told apart by well-known member names
+
+use std::io;
+
+use super::Content;
+use super::Sniffed;
+use super::le_u16;
+use super::le_u32;
+use super::le_u64;
+
+
+// libmagic MIME types of ZIP files that may be something more specific; OOXML
+// documents may still turn out to be macro-enabled
+pub const MIMES: &[&str] = &[
+  "application/zip",
+  "application/java-archive",
+  "application/octet-stream",
+  "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+  "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+  "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+];
+
+const EOCD_SIG:          u32 = 0x0605_4b50;
+const ZIP64_LOCATOR_SIG: u32 = 0x0706_4b50;
+const ZIP64_EOCD_SIG:    u32 = 0x0606_4b50;
+const CENTRAL_SIG:       u32 = 0x0201_4b50;
+const LOCAL_SIG:         u32 = 0x0403_4b50;
+
+const EOCD_LEN:          u64 = 22;
+const ZIP64_LOCATOR_LEN: u64 = 20;
+const ZIP64_EOCD_LEN:    u64 = 56;
+const CENTRAL_LEN:       usize = 46;
+const LOCAL_LEN:         u64 = 30;
+
+// The EOCD record may be followed by a comment of up to 64 KiB
+const MAX_TAIL_LEN:      u64 = EOCD_LEN + 0xffff;
+// Don't bother with central directories larger than this
+const MAX_CENTRAL_LEN:   u64 = 16 << 20;
+// The "mimetype" member is expected to be short
+const MAX_MIMETYPE_LEN:  u64 = 256;
+
+// OOXML part directories, with the MIME types of the plain and macro-enabled
+// documents; templates and slideshows only differ in the (compressed) content
+// types, so they pass for documents, whose extensions they are compatible with
+const OOXML: &[(&str, &str, &str)] = &[
+  (
+    "word/",
+    "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+    "application/vnd.ms-word.document.macroenabled.12",
+  ),
+  (
+    "xl/",
+    "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+    "application/vnd.ms-excel.sheet.macroenabled.12",
+  ),
+  (
+    "ppt/",
+    "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+    "application/vnd.ms-powerpoint.presentation.macroenabled.12",
+  ),
+];
+
+// Excel binary workbooks keep their parts in .bin files, and always allow
+// macros
+const XLSB_WORKBOOK: &str = "xl/workbook.bin";
+const XLSB:          &str = "application/vnd.ms-excel.sheet.binary.macroenabled.12";
+
+#[derive(Debug)]
+struct Entry {
+  name:   String,
+  method: u16,
+  size:   u64,
+  offset: u64,
+}
+
+// Offset and size of the central directory
+fn find_central(c: &Content) -> io::Result<Option<(u64, u64)>> {
+  let tail_len = c.len.min(MAX_TAIL_LEN);
+  let tail_offset = c.len - tail_len;
+  let tail = c.read_at(tail_offset, tail_len)?;
+
+  if (tail.len() as u64) < EOCD_LEN {
+    return Ok(None);
+  }
+
+  let eocd = match (0..=tail.len() - EOCD_LEN as usize)
+    .rev()
+    .find(|&i| le_u32(&tail, i) == Some(EOCD_SIG))
+  {
+    Some(i) => i,
+    None    => return Ok(None),
+  };
+
+  let size   = le_u32(&tail, eocd + 12).unwrap();
+  let offset = le_u32(&tail, eocd + 16).unwrap();
+
+  if size != u32::MAX && offset != u32::MAX {
+    return Ok(Some((offset as u64, size as u64)));
+  }
+
+  // ZIP64: the real values are in the ZIP64 EOCD record, which is pointed to
+  // by a locator right before the EOCD record
+  let eocd_offset = tail_offset + eocd as u64;
+
+  if eocd_offset < ZIP64_LOCATOR_LEN {
+    return Ok(None);
+  }
+
+  let locator = c.read_at(eocd_offset - ZIP64_LOCATOR_LEN, ZIP64_LOCATOR_LEN)?;
+
+  let zip64_offset = match (le_u32(&locator, 0), le_u64(&locator, 8)) {
+    (Some(ZIP64_LOCATOR_SIG), Some(o)) => o,
+    _                                  => return Ok(None),
+  };
+
+  let zip64 = c.read_at(zip64_offset, ZIP64_EOCD_LEN)?;
+
+  match (le_u32(&zip64, 0), le_u64(&zip64, 48), le_u64(&zip64, 40)) {
+    (Some(ZIP64_EOCD_SIG), Some(o), Some(s)) => Ok(Some((o, s))),
+    _                                        => Ok(None),
+  }
+}
+
+fn read_central(c: &Content) -> io::Result<Option<Vec<Entry>>> {
+  let (offset, size) = match find_central(c)? {
+    Some((o, s)) if s <= MAX_CENTRAL_LEN && o.saturating_add(s) <= c.len => (o, s),
+    _                                                                    => return Ok(None),
+  };
+
+  let central = c.read_at(offset, size)?;
+  let mut entries: Vec<Entry> = vec![];
+  let mut i = 0;
+
+  while le_u32(&central, i) == Some(CENTRAL_SIG) && i + CENTRAL_LEN <= central.len() {
+    let method   = le_u16(&central, i + 10).unwrap();
+    let size     = le_u32(&central, i + 20).unwrap();
+    let name_len = le_u16(&central, i + 28).unwrap() as usize;
+    let extra    = le_u16(&central, i + 30).unwrap() as usize;
+    let comment  = le_u16(&central, i + 32).unwrap() as usize;
+    let offset   = le_u32(&central, i + 42).unwrap();
+
+    let name = match central.get(i + CENTRAL_LEN..i + CENTRAL_LEN + name_len) {
+      Some(n) => String::from_utf8_lossy(n).into_owned(),
+      None    => break,
+    };
+
+    entries.push(Entry { name, method, size: size as u64, offset: offset as u64 });
+    i += CENTRAL_LEN + name_len + extra + comment;
+  }
+
+  Ok(Some(entries))
+}
+
+// Contents of a stored (uncompressed) "mimetype" member, if it looks like a
+// MIME type
+fn read_mimetype(c: &Content, e: &Entry) -> io::Result<Option<String>> {
+  if e.method != 0 || e.size > MAX_MIMETYPE_LEN {
+    return Ok(None);
+  }
+
+  let local = c.read_at(e.offset, LOCAL_LEN)?;
+
+  let data_offset = match (le_u32(&local, 0), le_u16(&local, 26), le_u16(&local, 28)) {
+    (Some(LOCAL_SIG), Some(n), Some(x)) => e.offset + LOCAL_LEN + n as u64 + x as u64,
+    _                                   => return Ok(None),
+  };
+
+  let data = c.read_at(data_offset, e.size)?;
+  let mime = String::from_utf8_lossy(&data).trim().to_string();
+
+  if mime.contains('/') && mime.chars().all(|c| c.is_ascii_graphic()) {
+    Ok(Some(mime))
+  } else {
+    Ok(None)
+  }
+}
+
+pub fn sniff(c: &Content, _mime: &str) -> io::Result<Option<Sniffed>> {
+  let entries = match read_central(c)? {
+    Some(entries) => entries,
+    None          => return Ok(None),
+  };
+
+  let has = |name: &str| entries.iter().any(|e| e.name == name);
+  let has_dir = |dir: &str| entries.iter().any(|e| e.name.starts_with(dir));
+
+  macro_rules! sniffed {
+    ($mime:expr, $detail:expr) => {
+      return Ok(Some(Sniffed { mime: $mime.to_string(), detail: $detail.to_string() }))
+    };
+  }
+
+  if let Some(e) = entries.iter().find(|e| e.name == "mimetype") {
+    if let Some(mime) = read_mimetype(c, e)? {
+      sniffed!(mime, "mimetype");
+    }
+  }
+
+  // APKs are JARs as well, so check them first
+  if has("AndroidManifest.xml") {
+    sniffed!("application/vnd.android.package-archive", "AndroidManifest.xml");
+  }
+
+  if has("[Content_Types].xml") {
+    if has(XLSB_WORKBOOK) {
+      sniffed!(XLSB, XLSB_WORKBOOK);
+    }
+
+    for (dir, mime, macro_mime) in OOXML {
+      let macros = format!("{}vbaProject.bin", dir);
+
+      if has(&macros) {
+        sniffed!(macro_mime, macros);
+      }
+
+      if has_dir(dir) {
+        sniffed!(mime, dir);
+      }
+    }
+  }
+
+  if has("META-INF/MANIFEST.MF") {
+    sniffed!("application/java-archive", "META-INF/MANIFEST.MF");
+  }
+
+  Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // A ZIP archive of stored members, with an archive comment
+  fn archive(members: &[(&str, &[u8])], comment: &[u8]) -> Vec<u8> {
+    let mut b: Vec<u8> = vec![];
+    let mut central: Vec<u8> = vec![];
+
+    for (name, data) in members {
+      let offset = b.len() as u32;
+
+      b.extend(&LOCAL_SIG.to_le_bytes());
+      b.extend(&[20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+      b.extend(&(data.len() as u32).to_le_bytes());
+      b.extend(&(data.len() as u32).to_le_bytes());
+      b.extend(&(name.len() as u16).to_le_bytes());
+      b.extend(&[0, 0]);
+      b.extend(name.as_bytes());
+      b.extend(*data);
+
+      central.extend(&CENTRAL_SIG.to_le_bytes());
+      central.extend(&[20, 0, 20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+      central.extend(&(data.len() as u32).to_le_bytes());
+      central.extend(&(data.len() as u32).to_le_bytes());
+      central.extend(&(name.len() as u16).to_le_bytes());
+      central.extend(&[0; 12]);
+      central.extend(&offset.to_le_bytes());
+      central.extend(name.as_bytes());
+    }
+
+    let offset = b.len() as u32;
+    b.extend(&central);
+
+    b.extend(&EOCD_SIG.to_le_bytes());
+    b.extend(&[0, 0, 0, 0]);
+    b.extend(&(members.len() as u16).to_le_bytes());
+    b.extend(&(members.len() as u16).to_le_bytes());
+    b.extend(&(central.len() as u32).to_le_bytes());
+    b.extend(&offset.to_le_bytes());
+    b.extend(&(comment.len() as u16).to_le_bytes());
+    b.extend(comment);
+    b
+  }
+
+  fn sniffed(members: &[(&str, &[u8])]) -> Option<String> {
+    let b = archive(members, b"");
+    sniff(&Content::from_bytes(&b), "application/zip").unwrap().map(|s| s.mime)
+  }
+
+  #[test]
+  fn mimetype_member() {
+    let odt = "application/vnd.oasis.opendocument.text";
+    assert_eq!(sniffed(&[("mimetype", odt.as_bytes()), ("content.xml", b"x")]), Some(odt.into()));
+
+    // Not a MIME type
+    assert_eq!(sniffed(&[("mimetype", b"hello world")]), None);
+  }
+
+  #[test]
+  fn ooxml_documents() {
+    let docx = sniffed(&[("[Content_Types].xml", b"x"), ("word/document.xml", b"x")]);
+    assert_eq!(docx.as_deref(), Some(OOXML[0].1));
+
+    let xlsm = sniffed(&[
+      ("[Content_Types].xml", b"x"),
+      ("xl/workbook.xml", b"x"),
+      ("xl/vbaProject.bin", b"x"),
+    ]);
+    assert_eq!(xlsm.as_deref(), Some(OOXML[1].2));
+
+    // Binary workbooks, with or without macros
+    let xlsb = sniffed(&[("[Content_Types].xml", b"x"), (XLSB_WORKBOOK, b"x")]);
+    assert_eq!(xlsb.as_deref(), Some(XLSB));
+
+    let xlsb = sniffed(&[
+      ("[Content_Types].xml", b"x"),
+      ("xl/vbaProject.bin", b"x"),
+      (XLSB_WORKBOOK, b"x"),
+    ]);
+    assert_eq!(xlsb.as_deref(), Some(XLSB));
+
+    // Part directories alone are not enough
+    assert_eq!(sniffed(&[("word/document.xml", b"x")]), None);
+  }
+
+  #[test]
+  fn java_archives() {
+    let apk = sniffed(&[("META-INF/MANIFEST.MF", b"x"), ("AndroidManifest.xml", b"x")]);
+    assert_eq!(apk.as_deref(), Some("application/vnd.android.package-archive"));
+
+    let jar = sniffed(&[("META-INF/MANIFEST.MF", b"x"), ("a/B.class", b"x")]);
+    assert_eq!(jar.as_deref(), Some("application/java-archive"));
+  }
+
+  #[test]
+  fn central_directory_walk() {
+    let members: &[(&str, &[u8])] = &[("a.txt", b"a"), ("dir/b.txt", b"bb")];
+    let b = archive(members, b"an archive comment");
+    let entries = read_central(&Content::from_bytes(&b)).unwrap().unwrap();
+
+    let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+    assert_eq!(names, ["a.txt", "dir/b.txt"]);
+    assert_eq!(entries[1].size, 2);
+    assert_eq!(sniffed(members), None);
+
+    // Truncated, or not a ZIP archive at all
+    assert!(read_central(&Content::from_bytes(&b[..b.len() - 40])).unwrap().is_none());
+    assert!(read_central(&Content::from_bytes(b"PK\x03\x04 not really")).unwrap().is_none());
+    assert!(read_central(&Content::from_bytes(b"")).unwrap().is_none());
+  }
+}
@@ -0,0 +1,139 @@
+// Built-in extension associations, generated by build.rs from data/*.types as
+// static tables so that nothing is parsed or built at startup; tables keyed
+// by MIME type, compound extension or command name are sorted by key
+
+use std::collections::HashMap;
+
+use regex::RegexSet;
+
+
+pub type Exts  = &'static [&'static str];
+pub type Table = &'static [(&'static str, Exts)];
+
+include!(concat!(env!("OUT_DIR"), "/tables.rs"));
+
+// The extensions for `key` in a sorted table
+pub fn get(table: Table, key: &str) -> Option<Exts> {
+  table.binary_search_by(|(k, _)| (*k).cmp(key)).ok().map(|i| table[i].1)
+}
+
+// Extensions given on the command line live as long as the built-in ones
+pub fn leak(exts: Vec<String>) -> Exts {
+  let exts: Vec<&'static str> = exts.into_iter().map(|e| &*Box::leak(e.into_boxed_str())).collect();
+  Box::leak(exts.into_boxed_slice())
+}
+
+pub fn owned(exts: Exts) -> Vec<String> {
+  exts.iter().map(|e| e.to_string()).collect()
+}
+
+// A sorted table with overrides on top
+pub struct Map {
+  table:     Table,
+  overrides: HashMap<String, Exts>,
+}
+
+impl Map {
+  pub fn new(table: Table, overrides: HashMap<String, Exts>) -> Map {
+    Map { table, overrides }
+  }
+
+  pub fn get(&self, key: &str) -> Option<Exts> {
+    self.overrides.get(key).copied().or_else(|| get(self.table, key))
+  }
+
+  pub fn is_override(&self, key: &str) -> bool {
+    self.overrides.contains_key(key)
+  }
+
+  // All extension lists in effect
+  pub fn values(&self) -> impl Iterator<Item = Exts> + '_ {
+    let overrides = &self.overrides;
+
+    overrides
+      .values()
+      .copied()
+      .chain(self.table.iter().filter(move |(k, _)| !overrides.contains_key(*k)).map(|(_, e)| *e))
+  }
+}
+
+// Description regexes, all tried at once, with overrides first
+pub struct Rules {
+  set:       RegexSet,
+  exts:      Vec<Exts>,
+  overrides: usize,
+}
+
+impl Rules {
+  pub fn new<'a>(overrides: &'a [(&'a str, Exts)], table: Table) -> Result<Rules, regex::Error> {
+    let table: &'a [(&'a str, Exts)] = table;
+    let rules: Vec<&(&str, Exts)> = overrides.iter().chain(table).collect();
+
+    let set = RegexSet::new(rules.iter().map(|(r, _)| r))?;
+    let exts = rules.into_iter().map(|(_, exts)| *exts).collect();
+
+    Ok(Rules { set, exts, overrides: overrides.len() })
+  }
+
+  // The regexes matching `desc` with their extensions, and whether they are
+  // overrides, in order
+  pub fn matches<'a>(&'a self, desc: &str) -> impl Iterator<Item = (&'a str, Exts, bool)> + 'a {
+    let patterns = self.set.patterns();
+
+    self
+      .set
+      .matches(desc)
+      .into_iter()
+      .map(move |i| (patterns[i].as_str(), self.exts[i], i < self.overrides))
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn tables_are_sorted() {
+    for table in [MIME_TYPES, COMPOUND_TYPES, COMPAT_TYPES, SHEBANG_TYPES].iter() {
+      assert!(table.windows(2).all(|w| w[0].0 < w[1].0));
+      assert!(table.iter().all(|(key, exts)| get(table, key) == Some(*exts)));
+    }
+
+    assert!(MIME_TYPES.iter().all(|(_, exts)| !exts.is_empty()));
+    assert_eq!(get(MIME_TYPES, "image/png"), Some(&["png"][..]));
+    assert_eq!(get(MIME_TYPES, "image/no-such-type"), None);
+  }
+
+  #[test]
+  fn multi_exts_are_longest_first() {
+    let dots: Vec<usize> = MULTI_EXTS.iter().map(|e| e.matches('.').count()).collect();
+
+    assert!(dots.iter().all(|d| *d > 0));
+    assert!(dots.windows(2).all(|w| w[0] >= w[1]));
+  }
+
+  #[test]
+  fn regexes_compile() {
+    let rules = Rules::new(&[], DESC_TYPES).unwrap();
+    assert_eq!(rules.exts.len(), DESC_TYPES.len());
+    assert!(RegexSet::new(GENERIC_DESCS).is_ok());
+
+    let desc = "PE32 executable (DLL) (console) Intel 80386, for MS Windows";
+    assert!(rules.matches(desc).any(|(_, exts, user)| exts == ["dll"] && !user));
+
+    assert!(Rules::new(&[("(", &["x"])], DESC_TYPES).is_err());
+  }
+
+  #[test]
+  fn overrides_shadow_the_table() {
+    let mut overrides: HashMap<String, Exts> = HashMap::new();
+    overrides.insert(String::from("image/png"), &["png8"]);
+    let map = Map::new(MIME_TYPES, overrides);
+
+    assert_eq!(map.get("image/png"), Some(&["png8"][..]));
+    assert_eq!(map.get("image/gif"), get(MIME_TYPES, "image/gif"));
+    assert_eq!(map.values().count(), MIME_TYPES.len());
+    assert!(!map.values().any(|exts| exts == ["png"]));
+  }
+}
@@ -0,0 +1,266 @@
+// Watch mode: wait for files to be written or moved into a directory with
+// inotify, and hand them over once nothing has touched them for a while
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
+
+#[cfg(target_os = "linux")]
+use std::ffi::CString;
+#[cfg(target_os = "linux")]
+use std::fs;
+#[cfg(target_os = "linux")]
+use std::io::Read;
+#[cfg(target_os = "linux")]
+use std::os::unix::ffi::OsStrExt;
+#[cfg(target_os = "linux")]
+use std::os::unix::ffi::OsStringExt;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::FromRawFd;
+
+
+// How long a file must be left alone before it is looked at
+pub const DEFAULT_SETTLE: Duration = Duration::from_secs(2);
+
+#[cfg(target_os = "linux")]
+const EVENT_LEN: usize = std::mem::size_of::<libc::inotify_event>();
+
+// NAME_MAX on Linux
+#[cfg(target_os = "linux")]
+const NAME_MAX: usize = 255;
+
+// Room for a batch of events, even with the longest names
+#[cfg(target_os = "linux")]
+const BUFFER_LEN: usize = 64 * (EVENT_LEN + NAME_MAX + 1);
+
+pub struct Watcher {
+  #[cfg(target_os = "linux")]
+  inotify: fs::File,
+  dir:     PathBuf,
+  settle:  Duration,
+  pending: HashMap<OsString, Instant>,
+  ignored: HashSet<OsString>,
+  // Events were lost since this was last checked
+  overflowed: bool,
+}
+
+impl Watcher {
+  #[cfg(target_os = "linux")]
+  pub fn new(dir: &Path, settle: Duration) -> io::Result<Watcher> {
+    let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+
+    if fd < 0 {
+      return Err(io::Error::last_os_error());
+    }
+
+    // Closed on drop from here on
+    let inotify = unsafe { fs::File::from_raw_fd(fd) };
+
+    let c_dir = CString::new(dir.as_os_str().as_bytes())
+      .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    // Partial writes only postpone a file, they never make it ready
+    let mask = libc::IN_CLOSE_WRITE
+      | libc::IN_MOVED_TO
+      | libc::IN_MODIFY
+      | libc::IN_MOVED_FROM
+      | libc::IN_DELETE
+      | libc::IN_ONLYDIR;
+
+    if unsafe { libc::inotify_add_watch(fd, c_dir.as_ptr(), mask) } < 0 {
+      return Err(io::Error::last_os_error());
+    }
+
+    Ok(Watcher {
+      inotify,
+      dir: dir.to_path_buf(),
+      settle,
+      pending: HashMap::new(),
+      ignored: HashSet::new(),
+      overflowed: false,
+    })
+  }
+
+  #[cfg(not(target_os = "linux"))]
+  pub fn new(_dir: &Path, _settle: Duration) -> io::Result<Watcher> {
+    Err(io::Error::new(io::ErrorKind::Other, "watching needs inotify, which is Linux only"))
+  }
+
+  // Don't report the file at `path` when it shows up next, e.g. because it
+  // was just renamed to that
+  pub fn ignore(&mut self, path: &Path) {
+    if let Some(name) = path.file_name() {
+      self.ignored.insert(name.to_os_string());
+    }
+  }
+
+  // Whether events were lost, and the whole directory looked at again, since
+  // this was last called
+  pub fn overflowed(&mut self) -> bool {
+    std::mem::replace(&mut self.overflowed, false)
+  }
+
+  // Block until some files have settled, and return their paths
+  pub fn wait(&mut self) -> io::Result<Vec<PathBuf>> {
+    loop {
+      let now = Instant::now();
+
+      let mut ready: Vec<OsString> = self
+        .pending
+        .iter()
+        .filter(|(_, deadline)| **deadline <= now)
+        .map(|(name, _)| name.clone())
+        .collect();
+
+      if !ready.is_empty() {
+        ready.sort();
+        ready.iter().for_each(|name| {
+          self.pending.remove(name);
+        });
+
+        return Ok(ready.into_iter().map(|name| self.dir.join(name)).collect());
+      }
+
+      let timeout = self.pending.values().min().map(|deadline| deadline.duration_since(now));
+
+      self.read_events(timeout)?;
+    }
+  }
+
+  #[cfg(target_os = "linux")]
+  fn read_events(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+    let mut pollfd = libc::pollfd {
+      fd:      self.inotify.as_raw_fd(),
+      events:  libc::POLLIN,
+      revents: 0,
+    };
+
+    // Round up, so that a deadline is never missed by less than a millisecond
+    let timeout_ms: libc::c_int = match timeout {
+      Some(t) => ((t.as_micros() + 999) / 1000).min(libc::c_int::MAX as u128) as libc::c_int,
+      None    => -1,
+    };
+
+    match unsafe { libc::poll(&mut pollfd, 1, timeout_ms) } {
+      0          => return Ok(()),
+      n if n < 0 => {
+        let e = io::Error::last_os_error();
+
+        return match e.kind() {
+          io::ErrorKind::Interrupted => Ok(()),
+          _                          => Err(e),
+        };
+      }
+      _          => (),
+    }
+
+    let mut buf: Vec<u8> = vec![0; BUFFER_LEN];
+    let len = self.inotify.read(&mut buf)?;
+    let mut offset = 0;
+
+    while offset + EVENT_LEN <= len {
+      let event: libc::inotify_event =
+        unsafe { std::ptr::read_unaligned(buf[offset..].as_ptr() as *const libc::inotify_event) };
+
+      let name_start = offset + EVENT_LEN;
+      let name_end = (name_start + event.len as usize).min(len);
+      offset = name_end;
+
+      // The watched directory itself is gone
+      if event.mask & libc::IN_IGNORED != 0 {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "the directory is gone"));
+      }
+
+      // The kernel queue was full and events were dropped
+      if event.mask & libc::IN_Q_OVERFLOW != 0 {
+        self.rescan()?;
+        continue;
+      }
+
+      // Names are NUL-padded
+      let name: Vec<u8> =
+        buf[name_start..name_end].iter().take_while(|b| **b != 0).copied().collect();
+
+      if name.is_empty() || event.mask & libc::IN_ISDIR != 0 {
+        continue;
+      }
+
+      let name = OsString::from_vec(name);
+      let deadline = Instant::now() + self.settle;
+
+      if event.mask & libc::IN_MOVED_TO != 0 && self.ignored.remove(&name) {
+        continue;
+      }
+
+      if event.mask & (libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO) != 0 {
+        self.pending.insert(name, deadline);
+      } else if event.mask & libc::IN_MODIFY != 0 {
+        if let Some(d) = self.pending.get_mut(&name) {
+          *d = deadline;
+        }
+      } else if event.mask & (libc::IN_MOVED_FROM | libc::IN_DELETE) != 0 {
+        self.pending.remove(&name);
+      }
+    }
+
+    Ok(())
+  }
+
+  // Any file may have been written without an event to tell, so wait for all
+  // of them to settle; renames by fixext may have been missed too
+  #[cfg(target_os = "linux")]
+  fn rescan(&mut self) -> io::Result<()> {
+    let deadline = Instant::now() + self.settle;
+
+    self.overflowed = true;
+    self.ignored.clear();
+
+    for entry in fs::read_dir(&self.dir)? {
+      let entry = entry?;
+
+      if !entry.file_type()?.is_dir() {
+        self.pending.insert(entry.file_name(), deadline);
+      }
+    }
+
+    Ok(())
+  }
+
+  #[cfg(not(target_os = "linux"))]
+  fn read_events(&mut self, _timeout: Option<Duration>) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "watching needs inotify, which is Linux only"))
+  }
+}
+
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn rescan() {
+    let dir = std::env::temp_dir().join(format!("fixext-watch-{}", std::process::id()));
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    fs::write(dir.join("a"), b"").unwrap();
+
+    let mut w = Watcher::new(&dir, Duration::from_secs(0)).unwrap();
+    w.ignore(&dir.join("b"));
+
+    let rescanned = w.rescan();
+    let paths = w.wait();
+    fs::remove_dir_all(&dir).unwrap();
+
+    rescanned.unwrap();
+    assert!(w.ignored.is_empty());
+    assert!(w.overflowed());
+    assert!(!w.overflowed());
+    assert_eq!(paths.unwrap(), [dir.join("a")]);
+  }
+}